@@ -1,8 +1,45 @@
+use bincode;
+use rand;
+use serde::de::Error as DeserializeError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::error::Error;
+use std::fmt;
+
+#[cfg(feature = "profiling")]
+use std::time::{Duration, Instant};
+
 const MEM_SIZE: usize = 0x1000;
-const GFX_SIZE_X: usize = 64;
+pub const GFX_SIZE_X: usize = 64;
 const GFX_SIZE_Y: usize = 32;
+const STACK_SIZE: usize = 16;
+// Programs are loaded here; everything below is reserved for the interpreter and font.
+const PROGRAM_START: u16 = 0x200;
+// Where the built-in font is loaded in memory.
+const FONT_BASE: usize = 0x050;
+// Size of one font glyph, in bytes.
+const FONT_GLYPH_SIZE: usize = 5;
 
-#[derive(Debug, PartialEq)]
+// Hex digits 0-F; each glyph is 4 pixels wide (the high nibble of each byte) and 5 rows tall.
+const FONT: [u8; 16 * FONT_GLYPH_SIZE] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x20, 0x60, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Opcode {
     // Not defined: opcode 0NNN (call RCA 1802 program).
     ClearDisplay,
@@ -73,6 +110,287 @@ pub enum Opcode {
     MemLoad { max_register: usize },
 }
 
+impl Opcode {
+    /// Encodes the opcode back into its 16-bit instruction word; the inverse of decoding.
+    #[allow(dead_code)]
+    pub fn encode(&self) -> u16 {
+        // Packs register/value fields into the x, y, and low nibble/byte positions.
+        let x = |register: usize| (register as u16 & 0xF) << 8;
+        let y = |register: usize| (register as u16 & 0xF) << 4;
+
+        match *self {
+            Opcode::ClearDisplay => 0x00E0,
+            Opcode::Return => 0x00EE,
+            Opcode::Jump { address } => 0x1000 | address & 0x0FFF,
+            Opcode::Call { address } => 0x2000 | address & 0x0FFF,
+            Opcode::SkipIfEqual { register, value } => 0x3000 | x(register) | value as u16,
+            Opcode::SkipIfNotEqual { register, value } => 0x4000 | x(register) | value as u16,
+            Opcode::SkipIfRegistersEqual { register1, register2 } => 0x5000 | x(register1) | y(register2),
+            Opcode::SetRegister { register, value } => 0x6000 | x(register) | value as u16,
+            Opcode::AddConstant { register, value } => 0x7000 | x(register) | value as u16,
+            // Vx is the target and Vy the source, even though source is declared first.
+            Opcode::CopyRegister { source, target } => 0x8000 | x(target) | y(source),
+            Opcode::BitOr { target, other } => 0x8001 | x(target) | y(other),
+            Opcode::BitAnd { target, other } => 0x8002 | x(target) | y(other),
+            Opcode::BitXor { target, other } => 0x8003 | x(target) | y(other),
+            Opcode::AddRegister { target, other } => 0x8004 | x(target) | y(other),
+            Opcode::SubtractRegister { target, other } => 0x8005 | x(target) | y(other),
+            Opcode::RightShift { target, source } => 0x8006 | x(target) | y(source),
+            Opcode::AltSubtractRegister { target, other } => 0x8007 | x(target) | y(other),
+            Opcode::LeftShift { target, source } => 0x800E | x(target) | y(source),
+            Opcode::SkipIfRegistersNotEqual { register1, register2 } => 0x9000 | x(register1) | y(register2),
+            Opcode::SetIndexRegister { value } => 0xA000 | value & 0x0FFF,
+            Opcode::OffsetJump { address } => 0xB000 | address & 0x0FFF,
+            // The mask is the low byte; the register is x, despite the field order.
+            Opcode::Rand { mask, register } => 0xC000 | x(register) | mask as u16,
+            Opcode::Display { x: vx, y: vy, height } => 0xD000 | x(vx) | y(vy) | (height & 0xF) as u16,
+            Opcode::SkipIfKeyPressed { key } => 0xE09E | x(key),
+            Opcode::SkipIfKeyNotPressed { key } => 0xE0A1 | x(key),
+            Opcode::GetDelayTimer { register } => 0xF007 | x(register),
+            Opcode::AwaitKeypress { register } => 0xF00A | x(register),
+            Opcode::SetDelayTimer { value } => 0xF015 | x(value),
+            Opcode::SetSoundTimer { value } => 0xF018 | x(value),
+            Opcode::IncrementIndexRegister { register } => 0xF01E | x(register),
+            Opcode::SetIndexToFont { register } => 0xF029 | x(register),
+            Opcode::StoreDecimal { register } => 0xF033 | x(register),
+            Opcode::MemDump { max_register } => 0xF055 | x(max_register),
+            Opcode::MemLoad { max_register } => 0xF065 | x(max_register),
+        }
+    }
+
+    // The highest register index the opcode refers to, if it refers to any.
+    fn highest_register(&self) -> Option<usize> {
+        match *self {
+            Opcode::ClearDisplay |
+            Opcode::Return |
+            Opcode::Jump { .. } |
+            Opcode::Call { .. } |
+            Opcode::SetIndexRegister { .. } |
+            Opcode::OffsetJump { .. } => None,
+            Opcode::SkipIfEqual { register, .. } |
+            Opcode::SkipIfNotEqual { register, .. } |
+            Opcode::SetRegister { register, .. } |
+            Opcode::AddConstant { register, .. } |
+            Opcode::Rand { register, .. } |
+            Opcode::SkipIfKeyPressed { key: register } |
+            Opcode::SkipIfKeyNotPressed { key: register } |
+            Opcode::GetDelayTimer { register } |
+            Opcode::AwaitKeypress { register } |
+            Opcode::SetDelayTimer { value: register } |
+            Opcode::SetSoundTimer { value: register } |
+            Opcode::IncrementIndexRegister { register } |
+            Opcode::SetIndexToFont { register } |
+            Opcode::StoreDecimal { register } |
+            Opcode::MemDump { max_register: register } |
+            Opcode::MemLoad { max_register: register } => Some(register),
+            Opcode::SkipIfRegistersEqual { register1, register2 } |
+            Opcode::SkipIfRegistersNotEqual { register1, register2 } => Some(register1.max(register2)),
+            Opcode::CopyRegister { source, target } |
+            Opcode::LeftShift { target, source } |
+            Opcode::RightShift { target, source } => Some(source.max(target)),
+            Opcode::BitOr { target, other } |
+            Opcode::BitAnd { target, other } |
+            Opcode::BitXor { target, other } |
+            Opcode::AddRegister { target, other } |
+            Opcode::SubtractRegister { target, other } |
+            Opcode::AltSubtractRegister { target, other } => Some(target.max(other)),
+            Opcode::Display { x, y, .. } => Some(x.max(y)),
+        }
+    }
+}
+
+// Renders opcodes as conventional CHIP-8 assembly, e.g. `LD V3, 0x72`.
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Opcode::ClearDisplay => write!(f, "CLS"),
+            Opcode::Return => write!(f, "RET"),
+            Opcode::Jump { address } => write!(f, "JP {:#X}", address),
+            Opcode::Call { address } => write!(f, "CALL {:#X}", address),
+            Opcode::SkipIfEqual { register, value } => write!(f, "SE V{:X}, {:#X}", register, value),
+            Opcode::SkipIfNotEqual { register, value } => write!(f, "SNE V{:X}, {:#X}", register, value),
+            Opcode::SkipIfRegistersEqual { register1, register2 } => write!(f, "SE V{:X}, V{:X}", register1, register2),
+            Opcode::SetRegister { register, value } => write!(f, "LD V{:X}, {:#X}", register, value),
+            Opcode::AddConstant { register, value } => write!(f, "ADD V{:X}, {:#X}", register, value),
+            Opcode::CopyRegister { source, target } => write!(f, "LD V{:X}, V{:X}", target, source),
+            Opcode::BitOr { target, other } => write!(f, "OR V{:X}, V{:X}", target, other),
+            Opcode::BitAnd { target, other } => write!(f, "AND V{:X}, V{:X}", target, other),
+            Opcode::BitXor { target, other } => write!(f, "XOR V{:X}, V{:X}", target, other),
+            Opcode::AddRegister { target, other } => write!(f, "ADD V{:X}, V{:X}", target, other),
+            Opcode::SubtractRegister { target, other } => write!(f, "SUB V{:X}, V{:X}", target, other),
+            Opcode::AltSubtractRegister { target, other } => write!(f, "SUBN V{:X}, V{:X}", target, other),
+            Opcode::LeftShift { target, source } => write!(f, "SHL V{:X}, V{:X}", target, source),
+            Opcode::RightShift { target, source } => write!(f, "SHR V{:X}, V{:X}", target, source),
+            Opcode::SkipIfRegistersNotEqual { register1, register2 } => write!(f, "SNE V{:X}, V{:X}", register1, register2),
+            Opcode::SetIndexRegister { value } => write!(f, "LD I, {:#X}", value),
+            Opcode::OffsetJump { address } => write!(f, "JP V0, {:#X}", address),
+            Opcode::Rand { mask, register } => write!(f, "RND V{:X}, {:#X}", register, mask),
+            Opcode::Display { x, y, height } => write!(f, "DRW V{:X}, V{:X}, {:#X}", x, y, height),
+            Opcode::SkipIfKeyPressed { key } => write!(f, "SKP V{:X}", key),
+            Opcode::SkipIfKeyNotPressed { key } => write!(f, "SKNP V{:X}", key),
+            Opcode::GetDelayTimer { register } => write!(f, "LD V{:X}, DT", register),
+            Opcode::AwaitKeypress { register } => write!(f, "LD V{:X}, K", register),
+            Opcode::SetDelayTimer { value } => write!(f, "LD DT, V{:X}", value),
+            Opcode::SetSoundTimer { value } => write!(f, "LD ST, V{:X}", value),
+            Opcode::IncrementIndexRegister { register } => write!(f, "ADD I, V{:X}", register),
+            Opcode::SetIndexToFont { register } => write!(f, "LD F, V{:X}", register),
+            Opcode::StoreDecimal { register } => write!(f, "LD B, V{:X}", register),
+            Opcode::MemDump { max_register } => write!(f, "LD [I], V{:X}", max_register),
+            Opcode::MemLoad { max_register } => write!(f, "LD V{:X}, [I]", max_register),
+        }
+    }
+}
+
+/// Disassembles a ROM two bytes at a time, pairing each instruction's address
+/// (as loaded at 0x200) with its mnemonic. Words that don't decode are shown
+/// as `DW 0xXXXX`, and a trailing odd byte as `DB 0xXX`. Bytes that wouldn't
+/// fit in memory past 0x200 are ignored.
+pub fn disassemble_rom(rom: &[u8]) -> Vec<(u16, String)> {
+    let loadable = rom.len().min(MEM_SIZE - PROGRAM_START as usize);
+    rom[..loadable].chunks(2).enumerate().map(|(i, bytes)| {
+        let address = PROGRAM_START + (i * 2) as u16;
+        let text = match *bytes {
+            [upper, lower] => {
+                let word = (upper as u16) << 8 | lower as u16;
+                match decode_opcode(word) {
+                    Some(opcode) => opcode.to_string(),
+                    None => format!("DW {:#06X}", word),
+                }
+            },
+            _ => format!("DB {:#04X}", bytes[0]),
+        };
+
+        (address, text)
+    }).collect()
+}
+
+// Patterns of the opcodes that execute_opcode can run, in numerical order.
+#[allow(dead_code)]
+const SUPPORTED_OPCODES: &[&str] = &[
+    "00E0", "00EE", "1NNN", "2NNN", "3XNN", "4XNN", "5XY0", "6XNN", "7XNN",
+    "8XY0", "8XY1", "8XY2", "8XY3", "8XY4", "8XY5", "8XY6", "8XYE",
+    "9XY0", "ANNN", "BNNN", "CXNN", "DXYN", "EX9E", "EXA1", "FX1E", "FX29", "FX33", "FX55", "FX65",
+];
+
+// Why an instruction couldn't be executed. Decoded register indices come from
+// 4-bit nibbles; only an opcode handed over by a pre-execute hook can exceed VF.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ExecError {
+    // Call with every stack slot already in use.
+    StackOverflow,
+    // Return with nothing on the stack.
+    StackUnderflow,
+    // Memory access past the last address.
+    MemoryOutOfBounds { address: usize },
+    // Key index above 0xF, read from a register.
+    KeyOutOfRange { key: usize },
+    // Register index above 0xF, in an opcode that wasn't decoded.
+    RegisterOutOfRange { register: usize },
+    // An opcode that decodes but has no execution semantics yet.
+    UnimplementedOpcode(Opcode),
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExecError::StackOverflow => write!(f, "stack overflow: more than {} nested calls", STACK_SIZE),
+            ExecError::StackUnderflow => write!(f, "stack underflow: return with an empty stack"),
+            ExecError::MemoryOutOfBounds { address } => write!(f, "memory address out of range: {:#X} > {:#X}", address, MEM_SIZE - 1),
+            ExecError::KeyOutOfRange { key } => write!(f, "key index out of range: {:#X} > 0xF", key),
+            ExecError::RegisterOutOfRange { register } => write!(f, "register index out of range: {:#X} > 0xF", register),
+            ExecError::UnimplementedOpcode(opcode) => write!(f, "unimplemented opcode: {}", opcode),
+        }
+    }
+}
+
+impl Error for ExecError {}
+
+// Why a save state couldn't be loaded.
+#[derive(Debug)]
+pub enum StateError {
+    // The bytes aren't a valid encoded state.
+    Malformed(bincode::Error),
+    // The state decoded, but its memory or framebuffer is the wrong size.
+    WrongSize,
+    // The state decoded, but holds a stack pointer or index register the VM can't run with.
+    InvalidState,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::Malformed(ref error) => write!(f, "malformed save state: {}", error),
+            StateError::WrongSize => write!(f, "save state memory or framebuffer is the wrong size"),
+            StateError::InvalidState => write!(f, "save state stack pointer or index register is out of range"),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+impl From<bincode::Error> for StateError {
+    fn from(error: bincode::Error) -> StateError {
+        StateError::Malformed(error)
+    }
+}
+
+// Everything a save state captures. serde can't handle arrays as big as
+// memory and gfx_memory, so those are stored as Vecs and checked on load.
+#[derive(Serialize, Deserialize)]
+struct SavedState {
+    memory: Vec<u8>,
+    registers: [u8; 16],
+    index_register: u16,
+    program_counter: u16,
+    gfx_memory: Vec<bool>,
+    delay_timer: u8,
+    sound_timer: u8,
+    stack: [u16; STACK_SIZE],
+    stack_pointer: u8,
+    keys: [bool; 16],
+}
+
+// How the program counter moved while executing an instruction.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ProgramFlow {
+    // The program counter advanced past the instruction as normal.
+    Sequential,
+    // The program counter was set by a jump, skip, call, or return.
+    Branched,
+}
+
+// What a pre-execute hook wants done with the instruction it was shown.
+#[derive(Debug, PartialEq)]
+#[allow(dead_code)]
+pub enum HookAction {
+    // Execute the instruction as decoded.
+    Proceed,
+    // Move past the instruction without executing it.
+    Skip,
+    // Execute this instruction instead.
+    Replace(Opcode),
+}
+
+// Called with each decoded instruction before it is executed.
+pub type PreExecuteHook = Box<dyn FnMut(&Opcode) -> HookAction>;
+
+// Supplies the random bytes used by Cxnn (Rand).
+pub type RandomSource = Box<dyn FnMut() -> u8>;
+
+// Called with the new beep state whenever the sound starts or stops.
+type SoundCallback = Box<dyn FnMut(bool)>;
+
+// Returns how many bytes the instruction starting with `word` takes up.
+// XO-CHIP's F000 NNNN long load is the only four-byte instruction.
+fn instruction_length(word: u16) -> u16 {
+    if word == 0xF000 {
+        4
+    }
+    else {
+        2
+    }
+}
+
 fn decode_opcode(opcode: u16) -> Option<Opcode> {
     // 0x00E0: Clear screen
     if opcode == 0x00E0 {
@@ -105,7 +423,7 @@ fn decode_opcode(opcode: u16) -> Option<Opcode> {
         });
     }
     // 0x5xy0: Skip if register Vx == register Vy
-    else if opcode & 0xF000 == 0x5000 {
+    else if opcode & 0xF00F == 0x5000 {
         return Some(Opcode::SkipIfRegistersEqual {
             register1: ((opcode & 0x0F00) >> 8) as usize,
             register2: ((opcode & 0x00F0) >> 4) as usize,
@@ -181,15 +499,15 @@ fn decode_opcode(opcode: u16) -> Option<Opcode> {
             other: ((opcode & 0x00F0) >> 4) as usize,
         })
     }
-    // 0x8xy8: Shift Vy left by one, store result in Vx, set VF to most sig. bit of Vy *before* shift
-    else if opcode & 0xF00F == 0x8008 {
+    // 0x8xyE: Shift Vy left by one, store result in Vx, set VF to most sig. bit of Vy *before* shift
+    else if opcode & 0xF00F == 0x800E {
         return Some(Opcode::LeftShift {
             target: ((opcode & 0x0F00) >> 8) as usize,
             source: ((opcode & 0x00F0) >> 4) as usize,
         })
     }
-    // 0x9xy: Skip if registers are not equal
-    else if opcode & 0xF000 == 0x9000 {
+    // 0x9xy0: Skip if registers are not equal
+    else if opcode & 0xF00F == 0x9000 {
         return Some(Opcode::SkipIfRegistersNotEqual {
             register1: ((opcode & 0x0F00) >> 8) as usize,
             register2: ((opcode & 0x00F0) >> 4) as usize,
@@ -288,6 +606,81 @@ fn decode_opcode(opcode: u16) -> Option<Opcode> {
     None
 }
 
+// A rectangle of pixels on the screen.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    // The smallest rectangle covering both self and other.
+    fn union(self, other: Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect {
+            x,
+            y,
+            width: (self.x + self.width).max(other.x + other.width) - x,
+            height: (self.y + self.height).max(other.y + other.height) - y,
+        }
+    }
+}
+
+// Toggles for behavior that differs between CHIP-8 interpreters.
+// The default is the modern COSMAC VIP-compatible set.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Quirks {
+    // 8xy0 (CopyRegister) also resets VF to 0. Only a few obscure interpreters
+    // do this, so it is off by default.
+    pub copy_resets_vf: bool,
+    // Fx55 (MemDump) and Fx65 (MemLoad) leave the index register incremented by
+    // the number of registers copied, as on the COSMAC VIP. Modern interpreters
+    // leave it alone, so this is off by default.
+    pub memory_increments_index: bool,
+    // 8xy6 and 8xyE (RightShift/LeftShift) shift Vx in place and ignore Vy,
+    // as SCHIP does. The COSMAC VIP shifts Vy into Vx, which is the default.
+    pub shift_in_place: bool,
+    // Bnnn (OffsetJump) is read as Bxnn and jumps to xnn + Vx, as SCHIP does.
+    // Off by default, where it jumps to nnn + V0.
+    pub jump_uses_vx: bool,
+    // Fx1E (IncrementIndexRegister) sets VF to 1 when the index register
+    // overflows past 0x0FFF, and to 0 otherwise, as the Amiga interpreter did.
+    // A few games rely on it; off by default.
+    pub index_overflow_sets_vf: bool,
+    // 8xy1, 8xy2, and 8xy3 (BitOr/BitAnd/BitXor) reset VF to 0 afterwards, as
+    // they incidentally did on the COSMAC VIP. On by default.
+    pub logic_resets_vf: bool,
+}
+
+impl Quirks {
+    /// The behavior SCHIP (and most ROMs written for it) expects.
+    #[allow(dead_code)]
+    pub fn schip() -> Quirks {
+        Quirks {
+            shift_in_place: true,
+            jump_uses_vx: true,
+            logic_resets_vf: false,
+            ..Quirks::default()
+        }
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks {
+            copy_resets_vf: false,
+            memory_increments_index: false,
+            shift_in_place: false,
+            jump_uses_vx: false,
+            index_overflow_sets_vf: false,
+            logic_resets_vf: true,
+        }
+    }
+}
+
 pub struct Chip8 {
     pub memory: [u8; MEM_SIZE],
     pub registers: [u8; 16],
@@ -298,140 +691,209 @@ pub struct Chip8 {
     pub gfx_memory: [bool; GFX_SIZE_X * GFX_SIZE_Y],
     pub delay_timer: u8,
     pub sound_timer: u8,
-    pub stack: [u8; 16],
+    pub stack: [u16; STACK_SIZE],
     pub stack_pointer: u8,
     pub keys: [bool; 16],
+    pub quirks: Quirks,
+    last_flow: ProgramFlow,
+    // Sprite rows the last Dxyn actually drew, after clipping at the bottom edge.
+    last_draw_rows: u8,
+    pre_execute_hook: Option<PreExecuteHook>,
+    random_source: RandomSource,
+    was_beeping: bool,
+    sound_callback: Option<SoundCallback>,
+    // Bounds of the pixels changed since the last take_dirty.
+    dirty: Option<Rect>,
+    #[cfg(feature = "profiling")]
+    decode_time: Duration,
+    #[cfg(feature = "profiling")]
+    execute_time: Duration,
+    // (reads, writes) of each register.
+    #[cfg(feature = "profiling")]
+    register_accesses: [(u64, u64); 16],
 }
 
 impl Chip8 {
     pub fn new() -> Chip8 {
-        Chip8 {
+        let mut vm = Chip8 {
             memory: [0; MEM_SIZE],
             registers: [0; 16],
             index_register: 0,
-            program_counter: 0,
+            program_counter: PROGRAM_START,
             gfx_memory: [false; GFX_SIZE_X * GFX_SIZE_Y],
             delay_timer: 0,
             sound_timer: 0,
-            stack: [0; 16],
+            stack: [0; STACK_SIZE],
             stack_pointer: 0,
             keys: [false; 16],
+            quirks: Quirks::default(),
+            last_flow: ProgramFlow::Sequential,
+            last_draw_rows: 0,
+            pre_execute_hook: None,
+            random_source: Box::new(rand::random),
+            was_beeping: false,
+            sound_callback: None,
+            dirty: None,
+            #[cfg(feature = "profiling")]
+            decode_time: Duration::new(0, 0),
+            #[cfg(feature = "profiling")]
+            execute_time: Duration::new(0, 0),
+            #[cfg(feature = "profiling")]
+            register_accesses: [(0, 0); 16],
+        };
+
+        vm.memory[FONT_BASE..FONT_BASE + FONT.len()].copy_from_slice(&FONT);
+        vm
+    }
+
+    /// Saves the full machine state (memory, registers, screen, timers, stack,
+    /// and keys) in a compact binary format. Quirks and hooks aren't included.
+    #[allow(dead_code)]
+    pub fn save_state(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("save states always serialize")
+    }
+
+    /// Restores a state produced by `save_state`. On error the VM is left untouched.
+    #[allow(dead_code)]
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), StateError> {
+        let state: SavedState = bincode::deserialize(bytes)?;
+        self.restore(state)
+    }
+
+    fn restore(&mut self, state: SavedState) -> Result<(), StateError> {
+        if state.memory.len() != MEM_SIZE || state.gfx_memory.len() != GFX_SIZE_X * GFX_SIZE_Y {
+            return Err(StateError::WrongSize);
         }
+
+        // The program counter needs no check: fetching past the end of memory is already an error.
+        if state.stack_pointer as usize > STACK_SIZE || state.index_register > 0x0FFF {
+            return Err(StateError::InvalidState);
+        }
+
+        self.memory.copy_from_slice(&state.memory);
+        self.registers = state.registers;
+        self.index_register = state.index_register;
+        self.program_counter = state.program_counter;
+        self.gfx_memory.copy_from_slice(&state.gfx_memory);
+        self.mark_dirty(Rect { x: 0, y: 0, width: GFX_SIZE_X, height: GFX_SIZE_Y });
+        self.delay_timer = state.delay_timer;
+        self.sound_timer = state.sound_timer;
+        self.stack = state.stack;
+        self.stack_pointer = state.stack_pointer;
+        self.keys = state.keys;
+        self.update_sound_state();
+        Ok(())
+    }
+
+    /// Creates a VM that follows the given interpreter quirks instead of the defaults.
+    #[allow(dead_code)]
+    pub fn with_quirks(quirks: Quirks) -> Chip8 {
+        let mut vm = Chip8::new();
+        vm.quirks = quirks;
+        vm
+    }
+
+    /// Puts the VM back in its power-on state: registers, timers, stack, keys,
+    /// and the screen are cleared and the program counter returns to 0x200.
+    /// Memory is left alone, so a loaded ROM can be run again from the start.
+    #[allow(dead_code)]
+    pub fn reset(&mut self) {
+        self.registers = [0; 16];
+        self.index_register = 0;
+        self.program_counter = PROGRAM_START;
+        self.clear_display();
+        self.delay_timer = 0;
+        self.sound_timer = 0;
+        self.stack = [0; STACK_SIZE];
+        self.stack_pointer = 0;
+        self.keys = [false; 16];
+        self.last_flow = ProgramFlow::Sequential;
+        self.last_draw_rows = 0;
+        self.update_sound_state();
     }
 
-    fn execute_opcode(&mut self, opcode: Opcode) {
+    fn execute_opcode(&mut self, opcode: Opcode) -> Result<(), ExecError> {
         match opcode {
+            Opcode::ClearDisplay => self.clear_display(),
+            Opcode::Return => {
+                if self.stack_pointer == 0 {
+                    return Err(ExecError::StackUnderflow);
+                }
+
+                self.stack_pointer -= 1;
+                self.program_counter = self.stack[self.stack_pointer as usize];
+            },
             Opcode::Jump { address } => self.program_counter = address,
-            Opcode::SkipIfEqual { register, value } => {
-                if register > 15 {
-                    panic!("Register index out of range: {} > 15", register);
+            Opcode::Call { address } => {
+                if self.stack_pointer as usize >= STACK_SIZE {
+                    return Err(ExecError::StackOverflow);
                 }
-                
+
+                // program_counter already points past the call, so that's where we return to.
+                self.stack[self.stack_pointer as usize] = self.program_counter;
+                self.stack_pointer += 1;
+                self.program_counter = address;
+            },
+            Opcode::SkipIfEqual { register, value } => {
                 let register_value = self.registers[register];
 
                 if register_value == value {
-                    // Skip the next instruction
-                    self.program_counter += 2;
+                    self.skip_next_instruction()?;
                 }
             },
             Opcode::SkipIfNotEqual { register, value } => {
-                if register > 15 {
-                    panic!("Register index out of range: {} > 15", register);
-                }
-                
                 let register_value = self.registers[register];
 
                 if register_value != value {
-                    // Skip the next instruction
-                    self.program_counter += 2;
+                    self.skip_next_instruction()?;
                 }
             },
             Opcode::SkipIfRegistersEqual { register1, register2 } => {
-                if register1 > 15 {
-                    panic!("Register index out of range: {} > 15", register1);
-                }
-
-                if register2 > 15 {
-                    panic!("Register index out of range: {} > 15", register2);
-                }
-
                 let r1_value = self.registers[register1];
                 let r2_value = self.registers[register2];
                 
                 if r1_value == r2_value {
-                    self.program_counter += 2;
+                    self.skip_next_instruction()?;
                 }
             },
             Opcode::SetRegister { register, value } => {
-                if register > 15 {
-                    panic!("Register index out of range: {} > 15", register);
-                }
-
                 self.registers[register] = value;
             },
             Opcode::AddConstant { register, value } => {
-                if register > 15 {
-                    panic!("Register index out of range: {} > 15", register);
-                }
-
                 let register_value = self.registers[register];
                 // Unsure: Is wrapping_add or clamping at max the correct behavior?
                 let sum = register_value.wrapping_add(value);
                 self.registers[register] = sum;
             },
             Opcode::CopyRegister { target, source } => {
-                if target > 15 {
-                    panic!("Register index out of range: {} > 15", target);
-                }
+                self.registers[target] = self.registers[source];
 
-                if source > 15 {
-                    panic!("Register index out of range: {} > 15", source);
+                if self.quirks.copy_resets_vf {
+                    self.registers[0xF] = 0;
                 }
-
-                self.registers[target] = self.registers[source];
             },
             Opcode::BitOr { target, other } => {
-                if target > 15 {
-                    panic!("Register index out of range: {} > 15", target);
-                }
+                self.registers[target] |= self.registers[other];
 
-                if other > 15 {
-                    panic!("Register index out of range: {} > 15", other);
+                if self.quirks.logic_resets_vf {
+                    self.registers[0xF] = 0;
                 }
-
-                self.registers[target] = self.registers[target] | self.registers[other];
             },
             Opcode::BitAnd { target, other } => {
-                if target > 15 {
-                    panic!("Register index out of range: {} > 15", target);
-                }
+                self.registers[target] &= self.registers[other];
 
-                if other > 15 {
-                    panic!("Register index out of range: {} > 15", other);
+                if self.quirks.logic_resets_vf {
+                    self.registers[0xF] = 0;
                 }
-
-                self.registers[target] = self.registers[target] & self.registers[other];
             },
             Opcode::BitXor { target, other } => {
-                if target > 15 {
-                    panic!("Register index out of range: {} > 15", target);
-                }
+                self.registers[target] ^= self.registers[other];
 
-                if other > 15 {
-                    panic!("Register index out of range: {} > 15", other);
+                if self.quirks.logic_resets_vf {
+                    self.registers[0xF] = 0;
                 }
-
-                self.registers[target] = self.registers[target] ^ self.registers[other];
             },
             Opcode::AddRegister { target, other } => {
-                if target > 15 {
-                    panic!("Register index out of range: {} > 15", target);
-                }
-
-                if other > 15 {
-                    panic!("Register index out of range: {} > 15", other);
-                }
-
                 let target_value = self.registers[target];
                 let other_value = self.registers[other];
 
@@ -448,14 +910,6 @@ impl Chip8 {
                 }
             },
             Opcode::SubtractRegister { target, other } => {
-                if target > 15 {
-                    panic!("Register index out of range: {} > 15", target);
-                }
-
-                if other > 15 {
-                    panic!("Register index out of range: {} > 15", other);
-                }
-
                 let target_value = self.registers[target];
                 let other_value = self.registers[other];
 
@@ -471,21 +925,256 @@ impl Chip8 {
                     self.registers[0xF] = 1;
                 }
             },
+            Opcode::RightShift { target, source } => {
+                let source_value = self.registers[self.shift_source(target, source)];
+
+                self.registers[target] = source_value >> 1;
+                // VF gets the bit that was shifted out.
+                self.registers[0xF] = source_value & 0x01;
+            },
+            Opcode::LeftShift { target, source } => {
+                let source_value = self.registers[self.shift_source(target, source)];
+
+                self.registers[target] = source_value << 1;
+                // VF gets the bit that was shifted out.
+                self.registers[0xF] = source_value >> 7;
+            },
+            Opcode::SkipIfRegistersNotEqual { register1, register2 } => {
+                let r1_value = self.registers[register1];
+                let r2_value = self.registers[register2];
+
+                if r1_value != r2_value {
+                    self.skip_next_instruction()?;
+                }
+            },
             Opcode::SetIndexRegister { value } => self.index_register = value,
-            _ => panic!("unimplemented opcode {:?}", opcode),
+            Opcode::OffsetJump { address } => {
+                let register = self.offset_jump_register(address);
+                self.program_counter = address + self.registers[register] as u16;
+            },
+            Opcode::Rand { mask, register } => {
+                self.registers[register] = (self.random_source)() & mask;
+            },
+            Opcode::Display { x, y, height } => {
+                // The starting coordinates wrap around the screen...
+                let origin_x = self.registers[x] as usize % GFX_SIZE_X;
+                let origin_y = self.registers[y] as usize % GFX_SIZE_Y;
+                // ...but the sprite itself is clipped at the right and bottom edges.
+                let rows = (height as usize).min(GFX_SIZE_Y - origin_y);
+
+                // Only the rows left after clipping are read, and all of them are
+                // checked before anything is drawn.
+                let address = self.index_register as usize;
+                if address + rows > MEM_SIZE {
+                    return Err(ExecError::MemoryOutOfBounds { address: address + rows - 1 });
+                }
+
+                self.last_draw_rows = rows as u8;
+                let mut collision = false;
+                for row in 0..rows {
+                    let pixel_y = origin_y + row;
+                    // Each byte is one 8-pixel row of the sprite; the MSB is the leftmost pixel.
+                    let sprite_row = self.memory[address + row];
+
+                    for column in 0..8 {
+                        let pixel_x = origin_x + column;
+                        if pixel_x >= GFX_SIZE_X {
+                            break;
+                        }
+
+                        if sprite_row & (0x80 >> column) == 0 {
+                            continue;
+                        }
+
+                        let pixel = &mut self.gfx_memory[pixel_y * GFX_SIZE_X + pixel_x];
+                        // Flipping a set pixel off is a collision.
+                        collision |= *pixel;
+                        *pixel = !*pixel;
+                        self.mark_dirty(Rect { x: pixel_x, y: pixel_y, width: 1, height: 1 });
+                    }
+                }
+
+                self.registers[0xF] = collision as u8;
+            },
+            Opcode::SkipIfKeyPressed { key } => {
+                if self.key_in_register(key)? {
+                    self.skip_next_instruction()?;
+                }
+            },
+            Opcode::SkipIfKeyNotPressed { key } => {
+                if !self.key_in_register(key)? {
+                    self.skip_next_instruction()?;
+                }
+            },
+            Opcode::IncrementIndexRegister { register } => {
+                let sum = self.index_register + self.registers[register] as u16;
+                // Addresses are 12 bits wide, so the index wraps around past 0x0FFF.
+                self.index_register = sum & 0x0FFF;
+
+                if self.quirks.index_overflow_sets_vf {
+                    self.registers[0xF] = (sum > 0x0FFF) as u8;
+                }
+            },
+            Opcode::SetIndexToFont { register } => {
+                // Only the low nibble picks a glyph; there are just 16 of them.
+                let glyph = (self.registers[register] & 0x0F) as usize;
+                self.index_register = (FONT_BASE + glyph * FONT_GLYPH_SIZE) as u16;
+            },
+            Opcode::StoreDecimal { register } => {
+                let address = self.index_register as usize;
+                if address + 2 >= MEM_SIZE {
+                    return Err(ExecError::MemoryOutOfBounds { address: address + 2 });
+                }
+
+                let value = self.registers[register];
+                self.memory[address] = value / 100;
+                self.memory[address + 1] = value / 10 % 10;
+                self.memory[address + 2] = value % 10;
+            },
+            Opcode::MemDump { max_register } => {
+                let address = self.index_register as usize;
+                if address + max_register >= MEM_SIZE {
+                    return Err(ExecError::MemoryOutOfBounds { address: address + max_register });
+                }
+
+                self.memory[address..=address + max_register].copy_from_slice(&self.registers[0..=max_register]);
+
+                if self.quirks.memory_increments_index {
+                    // Like Fx1E, the index wraps around past 0x0FFF.
+                    self.index_register = (self.index_register + max_register as u16 + 1) & 0x0FFF;
+                }
+            },
+            Opcode::MemLoad { max_register } => {
+                let address = self.index_register as usize;
+                if address + max_register >= MEM_SIZE {
+                    return Err(ExecError::MemoryOutOfBounds { address: address + max_register });
+                }
+
+                self.registers[0..=max_register].copy_from_slice(&self.memory[address..=address + max_register]);
+
+                if self.quirks.memory_increments_index {
+                    // Like Fx1E, the index wraps around past 0x0FFF.
+                    self.index_register = (self.index_register + max_register as u16 + 1) & 0x0FFF;
+                }
+            },
+            _ => return Err(ExecError::UnimplementedOpcode(opcode)),
+        }
+
+        Ok(())
+    }
+
+    // The register a shift reads from: Vy normally, or Vx itself under the shift-in-place quirk.
+    fn shift_source(&self, target: usize, source: usize) -> usize {
+        if self.quirks.shift_in_place { target } else { source }
+    }
+
+    // The register Bnnn offsets by: V0 normally, or the address's high nibble under the Bxnn quirk.
+    fn offset_jump_register(&self, address: u16) -> usize {
+        if self.quirks.jump_uses_vx {
+            ((address & 0x0F00) >> 8) as usize
+        }
+        else {
+            0
+        }
+    }
+
+    // Reads the key whose index is held in a register; ROMs can store any byte there.
+    fn key_in_register(&self, register: usize) -> Result<bool, ExecError> {
+        let key = self.registers[register] as usize;
+        match self.keys.get(key) {
+            Some(&pressed) => Ok(pressed),
+            None => Err(ExecError::KeyOutOfRange { key }),
         }
     }
 
-    fn process_next_opcode(&mut self) {
+    // Tallies which registers an instruction reads and writes, as bitmasks where bit n is Vn.
+    #[cfg(feature = "profiling")]
+    fn count_register_accesses(&mut self, opcode: &Opcode) {
+        let bit = |register: usize| 1u32 << register;
+        let up_to = |register: usize| (1u32 << (register + 1)) - 1;
+
+        let (reads, writes) = match *opcode {
+            Opcode::SkipIfEqual { register, .. } |
+            Opcode::SkipIfNotEqual { register, .. } => (bit(register), 0),
+            Opcode::SkipIfRegistersEqual { register1, register2 } |
+            Opcode::SkipIfRegistersNotEqual { register1, register2 } => (bit(register1) | bit(register2), 0),
+            Opcode::SetRegister { register, .. } => (0, bit(register)),
+            Opcode::AddConstant { register, .. } => (bit(register), bit(register)),
+            Opcode::CopyRegister { source, target } => {
+                let vf = if self.quirks.copy_resets_vf { bit(0xF) } else { 0 };
+                (bit(source), bit(target) | vf)
+            },
+            Opcode::BitOr { target, other } |
+            Opcode::BitAnd { target, other } |
+            Opcode::BitXor { target, other } => {
+                let vf = if self.quirks.logic_resets_vf { bit(0xF) } else { 0 };
+                (bit(target) | bit(other), bit(target) | vf)
+            },
+            Opcode::AddRegister { target, other } |
+            Opcode::SubtractRegister { target, other } |
+            Opcode::AltSubtractRegister { target, other } => (bit(target) | bit(other), bit(target) | bit(0xF)),
+            Opcode::LeftShift { target, source } |
+            Opcode::RightShift { target, source } => {
+                (bit(self.shift_source(target, source)), bit(target) | bit(0xF))
+            },
+            Opcode::OffsetJump { address } => (bit(self.offset_jump_register(address)), 0),
+            Opcode::Rand { register, .. } |
+            Opcode::GetDelayTimer { register } |
+            Opcode::AwaitKeypress { register } => (0, bit(register)),
+            Opcode::Display { x, y, .. } => (bit(x) | bit(y), bit(0xF)),
+            Opcode::IncrementIndexRegister { register } => {
+                let vf = if self.quirks.index_overflow_sets_vf { bit(0xF) } else { 0 };
+                (bit(register), vf)
+            },
+            Opcode::SkipIfKeyPressed { key: register } |
+            Opcode::SkipIfKeyNotPressed { key: register } |
+            Opcode::SetDelayTimer { value: register } |
+            Opcode::SetSoundTimer { value: register } |
+            Opcode::SetIndexToFont { register } |
+            Opcode::StoreDecimal { register } => (bit(register), 0),
+            Opcode::MemDump { max_register } => (up_to(max_register), 0),
+            Opcode::MemLoad { max_register } => (0, up_to(max_register)),
+            Opcode::ClearDisplay |
+            Opcode::Return |
+            Opcode::Jump { .. } |
+            Opcode::Call { .. } |
+            Opcode::SetIndexRegister { .. } => (0, 0),
+        };
+
+        for (register, counts) in self.register_accesses.iter_mut().enumerate() {
+            counts.0 += ((reads >> register) & 1) as u64;
+            counts.1 += ((writes >> register) & 1) as u64;
+        }
+    }
+
+    fn fetch_word(&self, address: u16) -> Result<u16, ExecError> {
+        let address = address as usize;
+        if address + 1 >= MEM_SIZE {
+            return Err(ExecError::MemoryOutOfBounds { address: address + 1 });
+        }
+
+        // Is a u16 value - fetch two u8s and merge them.
+        let upper = self.memory[address] as u16;
+        let lower = self.memory[address + 1] as u16;
+        // Combine them: shift upper into the upper 8 bits of the u16
+        // (remember, upper is only 8 significant bits - it was originally a u8)
+        // Then binary-or the lower value into the space that upper used to occupy
+        Ok(upper << 8 | lower)
+    }
+
+    // Moves the program counter past the next instruction, however long it is.
+    fn skip_next_instruction(&mut self) -> Result<(), ExecError> {
+        let next_word = self.fetch_word(self.program_counter)?;
+        self.program_counter += instruction_length(next_word);
+        Ok(())
+    }
+
+    fn process_next_opcode(&mut self) -> Result<(), ExecError> {
+        let start_counter = self.program_counter;
+
         // Fetch latest opcode.
         // Opcode is located in memory at the program_counter index
-        // Is a u16 value - fetch two u8s and merge them.
-        let opcode_upper = self.memory[self.program_counter as usize] as u16;
-        let opcode_lower = self.memory[self.program_counter as usize + 1] as u16;
-        // Combine them: shift opcode_upper into the upper 8 bits of the u16
-        // (remember, opcode_upper is only 8 significant bits - it was originally a u8)
-        // Then binary-or the lower value into the space that opcode_upper used to occupy
-        let opcode = opcode_upper << 8 | opcode_lower;
+        let opcode = self.fetch_word(self.program_counter)?;
 
         // Increment the program counter so we move past the instruction
         // Do this *here* so that if program_counter is changed, this change is overwritten
@@ -493,103 +1182,894 @@ impl Chip8 {
 
         // decode_opcode can return None; in the interests of making testing, etc. easier
         // this is not handled at all.
-        if let Some(decoded_opcode) = decode_opcode(opcode) {
-            self.execute_opcode(decoded_opcode);
+        #[cfg(feature = "profiling")]
+        let decode_start = Instant::now();
+        let decoded_opcode = decode_opcode(opcode);
+        #[cfg(feature = "profiling")]
+        {
+            self.decode_time += decode_start.elapsed();
         }
-    }
 
-    /// Steps the chip8 VM.
-    /// This does two things (in order):
-    /// * Decodes and executes the current opcode
-    /// * Decrements the delay and sound timers
-    pub fn step(&mut self) {
-        // Process the current instruction
-        self.process_next_opcode();
+        let mut result = Ok(());
+        if let Some(decoded_opcode) = decoded_opcode {
+            let action = match self.pre_execute_hook {
+                Some(ref mut hook) => hook(&decoded_opcode),
+                None => HookAction::Proceed,
+            };
 
-        // Decrement timers
-        if self.delay_timer > 0 {
-            self.delay_timer -= 1;
-        }
+            let opcode = match action {
+                HookAction::Proceed => Some(decoded_opcode),
+                HookAction::Skip => None,
+                HookAction::Replace(replacement) => Some(replacement),
+            };
 
-        if self.sound_timer > 0 {
-            self.sound_timer -= 1;
+            if let Some(opcode) = opcode {
+                // A hook's replacement never went through the decoder, so its
+                // registers are checked before anything indexes with them.
+                match opcode.highest_register() {
+                    Some(register) if register > 0xF => result = Err(ExecError::RegisterOutOfRange { register }),
+                    _ => {
+                        #[cfg(feature = "profiling")]
+                        self.count_register_accesses(&opcode);
+
+                        #[cfg(feature = "profiling")]
+                        let execute_start = Instant::now();
+                        result = self.execute_opcode(opcode);
+                        #[cfg(feature = "profiling")]
+                        {
+                            self.execute_time += execute_start.elapsed();
+                        }
+                    },
+                }
+            }
         }
-    }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+        // If the program counter isn't just past the instruction, something moved it.
+        // This is recorded even when the instruction failed, so the flow never goes stale.
+        self.last_flow = if self.program_counter == start_counter + 2 {
+            ProgramFlow::Sequential
+        }
+        else {
+            ProgramFlow::Branched
+        };
 
-    #[test]
-    fn step_decrements_timers() {
-        let mut vm = Chip8::new();
-        vm.delay_timer = 30;
-        vm.sound_timer = 19;
-        vm.step();
-        assert_eq!(vm.delay_timer, 29);
-        assert_eq!(vm.sound_timer, 18);
+        result
+    }
 
-        // Make sure we don't panic due to subtract w/ overflow:
-        vm.sound_timer = 0;
-        vm.step();
-        assert_eq!(vm.sound_timer, 0);
+    /// Returns the total time spent (decoding, executing) instructions so far.
+    #[cfg(feature = "profiling")]
+    #[allow(dead_code)]
+    pub fn timing_breakdown(&self) -> (Duration, Duration) {
+        (self.decode_time, self.execute_time)
     }
 
-    mod opcode_executing {
-        use super::*;
+    /// Returns how many times each register has been (read, written) by executed instructions.
+    #[cfg(feature = "profiling")]
+    #[allow(dead_code)]
+    pub fn register_access_counts(&self) -> [(u64, u64); 16] {
+        self.register_accesses
+    }
 
-        #[test]
-        fn jump() {
-            let mut vm = Chip8::new();
-            vm.execute_opcode(Opcode::Jump { address: 0x09DE });
-            assert_eq!(vm.program_counter, 0x09DE);
+    /// Presses or releases one of the 16 keys (0x0-0xF) on the keypad.
+    #[allow(dead_code)]
+    pub fn set_key(&mut self, key: usize, pressed: bool) {
+        if key > 0xF {
+            panic!("Key index out of range: {:#X} > 0xF", key);
         }
 
-        #[test]
-        fn set_idx_reg() {
-            let mut vm = Chip8::new();
-            vm.execute_opcode(Opcode::SetIndexRegister { value: 0x0387 });
-            assert_eq!(vm.index_register, 0x0387);
-        }
+        self.keys[key] = pressed;
+    }
 
-        #[test]
-        fn skip_if_eq_const() {
-            let mut vm = Chip8::new();
-            vm.execute_opcode(Opcode::SkipIfEqual { register: 0xA, value: 0x32 });
-            // Scenario 1: register A is 0, but we expect 0x32.
-            // This will not skip the next instruction. The program
-            // counter can thus be expected to be 0x0000.
-            assert_eq!(vm.program_counter, 0x0000);
-            
-            // Reset the program counter.
-            vm.program_counter = 0x0000;
-            // Scenario 2: register A is now 0x32, and we expect
-            // 0x32. This *will* skip the next instruction. The
-            // program counter should be 0x0002.
-            vm.registers[0x0A] = 0x32;
-            vm.execute_opcode(Opcode::SkipIfEqual { register: 0xA, value: 0x32 });
-            assert_eq!(vm.program_counter, 0x0002);
+    /// Returns whether one of the 16 keys (0x0-0xF) on the keypad is held down.
+    #[allow(dead_code)]
+    pub fn is_key_pressed(&self, key: usize) -> bool {
+        if key > 0xF {
+            panic!("Key index out of range: {:#X} > 0xF", key);
         }
 
-        #[test]
-        fn skip_if_not_eq_const() {
-            // This test is the reverse of skip_if_eq_const.
-            let mut vm = Chip8::new();
-            vm.execute_opcode(Opcode::SkipIfNotEqual { register: 0xA, value: 0x32 });
-            // Scenario 1: register A is 0, but we expect 0x32.
-            // This will skip the next instruction. The program
-            // counter can thus be expected to be 0x0002.
-            assert_eq!(vm.program_counter, 0x0002);
-            
-            // Reset the program counter.
-            vm.program_counter = 0x0000;
-            // Scenario 2: register A is now 0x32, and we expect
-            // 0x32. This will not skip the next instruction. The
-            // program counter should be 0x0000.
-            vm.registers[0x0A] = 0x32;
-            vm.execute_opcode(Opcode::SkipIfNotEqual { register: 0xA, value: 0x32 });
-            assert_eq!(vm.program_counter, 0x0000);
+        self.keys[key]
+    }
+
+    /// Blanks the screen, the same as executing 0x00E0.
+    pub fn clear_display(&mut self) {
+        if self.gfx_memory.iter().any(|&pixel| pixel) {
+            self.mark_dirty(Rect { x: 0, y: 0, width: GFX_SIZE_X, height: GFX_SIZE_Y });
+        }
+
+        self.gfx_memory = [false; GFX_SIZE_X * GFX_SIZE_Y];
+    }
+
+    /// Returns the bounds of every pixel that changed since the last call, or
+    /// `None` if nothing did, and starts tracking afresh. Only changes made by
+    /// the VM (drawing, clearing, loading a state) are tracked, not direct
+    /// writes to `gfx_memory`.
+    #[allow(dead_code)]
+    pub fn take_dirty(&mut self) -> Option<Rect> {
+        self.dirty.take()
+    }
+
+    fn mark_dirty(&mut self, rect: Rect) {
+        self.dirty = Some(match self.dirty {
+            Some(dirty) => dirty.union(rect),
+            None => rect,
+        });
+    }
+
+    /// Returns a view of the framebuffer, row-major, one `bool` per pixel.
+    #[allow(dead_code)]
+    pub fn gfx(&self) -> &[bool] {
+        &self.gfx_memory
+    }
+
+    /// Returns whether the pixel at (`x`, `y`) is lit; (0, 0) is the top left.
+    #[allow(dead_code)]
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        if x >= GFX_SIZE_X || y >= GFX_SIZE_Y {
+            panic!("Pixel out of range: ({}, {}) is outside {}x{}", x, y, GFX_SIZE_X, GFX_SIZE_Y);
+        }
+
+        self.gfx_memory[y * GFX_SIZE_X + x]
+    }
+
+    /// Packs the framebuffer eight pixels per byte, row-major, with the MSB of
+    /// each byte as the leftmost of its eight pixels. Each row is 8 bytes.
+    #[allow(dead_code)]
+    pub fn framebuffer_bytes(&self) -> [u8; GFX_SIZE_X * GFX_SIZE_Y / 8] {
+        let mut bytes = [0; GFX_SIZE_X * GFX_SIZE_Y / 8];
+        bytes.copy_from_slice(&self.framebuffer_with_bit_order(true));
+        bytes
+    }
+
+    /// Packs the framebuffer eight pixels per byte, row-major.
+    /// With `msb_first`, the leftmost pixel of each group of eight is the most
+    /// significant bit; otherwise it is the least significant bit.
+    #[allow(dead_code)]
+    pub fn framebuffer_with_bit_order(&self, msb_first: bool) -> Vec<u8> {
+        self.gfx_memory.chunks(8).map(|pixels| {
+            pixels.iter().enumerate().fold(0, |byte, (i, &pixel)| {
+                if !pixel {
+                    return byte;
+                }
+
+                let bit = if msb_first { 7 - i } else { i };
+                byte | (1 << bit)
+            })
+        }).collect()
+    }
+
+    /// Returns the patterns (e.g. `8XY4`) of every opcode this build can execute.
+    #[allow(dead_code)]
+    pub fn supported_opcodes(&self) -> Vec<&'static str> {
+        SUPPORTED_OPCODES.to_vec()
+    }
+
+    /// Returns how the program counter moved during the last step:
+    /// sequentially onto the next instruction, or by a branch.
+    /// A step whose instruction fails still updates this; one that can't
+    /// even fetch its instruction leaves it as it was.
+    #[allow(dead_code)]
+    pub fn last_flow(&self) -> ProgramFlow {
+        self.last_flow
+    }
+
+    /// Returns how many rows the last sprite draw covered once rows past the
+    /// bottom of the screen were clipped off.
+    #[allow(dead_code)]
+    pub fn last_draw_rows(&self) -> u8 {
+        self.last_draw_rows
+    }
+
+    /// Installs a hook that sees every decoded instruction before it runs,
+    /// and can let it proceed, skip it, or swap in a different instruction.
+    #[allow(dead_code)]
+    pub fn set_pre_execute_hook(&mut self, hook: PreExecuteHook) {
+        self.pre_execute_hook = Some(hook);
+    }
+
+    /// Replaces the source of random bytes used by Rand (0xCxnn).
+    /// By default the VM uses the thread-local generator from `rand`.
+    #[allow(dead_code)]
+    pub fn set_random_source(&mut self, source: RandomSource) {
+        self.random_source = source;
+    }
+
+    /// Encodes `op` and writes its two bytes, high byte first, at `addr`.
+    #[allow(dead_code)]
+    pub fn poke_opcode(&mut self, addr: u16, op: &Opcode) -> Result<(), ExecError> {
+        let address = addr as usize;
+        if address + 1 >= MEM_SIZE {
+            return Err(ExecError::MemoryOutOfBounds { address: address + 1 });
+        }
+
+        let word = op.encode();
+        self.memory[address] = (word >> 8) as u8;
+        self.memory[address + 1] = word as u8;
+        Ok(())
+    }
+
+    /// Boots a fresh machine with `rom` loaded at the initial program counter,
+    /// steps it exactly `cycles` times, and returns the resulting framebuffer.
+    /// A ROM that doesn't fit in memory fails before anything runs.
+    pub fn run_and_capture(rom: &[u8], cycles: usize) -> Result<Vec<bool>, ExecError> {
+        let mut vm = Chip8::new();
+        let start = vm.program_counter as usize;
+        if start + rom.len() > MEM_SIZE {
+            // Report the last byte the ROM would have needed.
+            return Err(ExecError::MemoryOutOfBounds { address: start + rom.len() - 1 });
+        }
+
+        vm.memory[start..start + rom.len()].copy_from_slice(rom);
+        vm.run(cycles)?;
+
+        Ok(vm.gfx_memory.to_vec())
+    }
+
+    /// Steps the chip8 VM: decodes and executes the current opcode.
+    /// This doesn't touch the timers; see `tick_timers`.
+    pub fn step(&mut self) -> Result<(), ExecError> {
+        let result = self.process_next_opcode();
+        self.update_sound_state();
+        result
+    }
+
+    /// Steps the VM `cycles` times, stopping at the first instruction that fails.
+    pub fn run(&mut self, cycles: usize) -> Result<(), ExecError> {
+        for _ in 0..cycles {
+            self.step()?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs one 60 Hz frame: `cycles_per_frame` steps, then a single timer tick.
+    #[allow(dead_code)]
+    pub fn run_frame(&mut self, cycles_per_frame: usize) -> Result<(), ExecError> {
+        self.run(cycles_per_frame)?;
+        self.tick_timers();
+        Ok(())
+    }
+
+    /// Decrements the delay and sound timers. These count down at 60 Hz
+    /// regardless of the CPU clock, so call this once every 1/60s.
+    #[allow(dead_code)]
+    pub fn tick_timers(&mut self) {
+        if self.delay_timer > 0 {
+            self.delay_timer -= 1;
+        }
+
+        if self.sound_timer > 0 {
+            self.sound_timer -= 1;
+        }
+
+        self.update_sound_state();
+    }
+
+    /// Returns whether the buzzer should be sounding, i.e. the sound timer is running.
+    pub fn is_beeping(&self) -> bool {
+        self.sound_timer > 0
+    }
+
+    /// Installs a callback that fires once when the beep starts (with `true`)
+    /// and once when it stops (with `false`). Changes are picked up after each
+    /// `step`, `tick_timers`, or `reset`.
+    #[allow(dead_code)]
+    pub fn on_sound(&mut self, callback: impl FnMut(bool) + 'static) {
+        self.sound_callback = Some(Box::new(callback));
+    }
+
+    // Fires the sound callback if the beep state changed since the last check.
+    fn update_sound_state(&mut self) {
+        let beeping = self.is_beeping();
+        if beeping == self.was_beeping {
+            return;
+        }
+
+        self.was_beeping = beeping;
+        if let Some(ref mut callback) = self.sound_callback {
+            callback(beeping);
+        }
+    }
+}
+
+impl Serialize for Chip8 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SavedState {
+            memory: self.memory.to_vec(),
+            registers: self.registers,
+            index_register: self.index_register,
+            program_counter: self.program_counter,
+            gfx_memory: self.gfx_memory.to_vec(),
+            delay_timer: self.delay_timer,
+            sound_timer: self.sound_timer,
+            stack: self.stack,
+            stack_pointer: self.stack_pointer,
+            keys: self.keys,
+        }.serialize(serializer)
+    }
+}
+
+// Deserialized VMs get the default quirks, hooks, and random source.
+impl<'de> Deserialize<'de> for Chip8 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Chip8, D::Error> {
+        let state = SavedState::deserialize(deserializer)?;
+        let mut vm = Chip8::new();
+        vm.restore(state).map_err(D::Error::custom)?;
+        Ok(vm)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn tick_timers_decrements_timers() {
+        let mut vm = Chip8::new();
+        vm.delay_timer = 30;
+        vm.sound_timer = 19;
+        vm.tick_timers();
+        assert_eq!(vm.delay_timer, 29);
+        assert_eq!(vm.sound_timer, 18);
+
+        // Make sure we don't panic due to subtract w/ overflow:
+        vm.sound_timer = 0;
+        vm.tick_timers();
+        assert_eq!(vm.sound_timer, 0);
+    }
+
+    #[test]
+    fn sound_edges() {
+        let mut vm = Chip8::new();
+        let edges = Rc::new(RefCell::new(Vec::new()));
+        let recorded = edges.clone();
+        vm.on_sound(move |beeping| recorded.borrow_mut().push(beeping));
+        assert!(!vm.is_beeping());
+
+        vm.sound_timer = 3;
+        assert!(vm.is_beeping());
+        for _ in 0..5 {
+            vm.tick_timers();
+        }
+
+        assert!(!vm.is_beeping());
+        assert_eq!(*edges.borrow(), vec![true, false]);
+    }
+
+    #[test]
+    fn step_leaves_timers_alone() {
+        let mut vm = Chip8::new();
+        // 0x7001: V0 += 1; 0x1200: Jump to 0x200
+        vm.memory[0x200..0x204].copy_from_slice(&[0x70, 0x01, 0x12, 0x00]);
+        vm.delay_timer = 30;
+        vm.sound_timer = 19;
+        for _ in 0..100 {
+            vm.step().unwrap();
+        }
+
+        assert_eq!(vm.registers[0], 50);
+        assert_eq!(vm.delay_timer, 30);
+        assert_eq!(vm.sound_timer, 19);
+    }
+
+    #[test]
+    fn reset() {
+        let mut vm = Chip8::new();
+        // 0x6E72: VE = 0x72; 0x2300: Call 0x300; 0x7101 at 0x300: V1 += 1
+        vm.memory[0x200..0x204].copy_from_slice(&[0x6E, 0x72, 0x23, 0x00]);
+        vm.memory[0x300..0x302].copy_from_slice(&[0x71, 0x01]);
+        vm.delay_timer = 30;
+        vm.set_key(0x5, true);
+        for _ in 0..3 {
+            vm.step().unwrap();
+        }
+        assert_eq!(vm.program_counter, 0x0302);
+
+        vm.reset();
+        assert_eq!(vm.program_counter, 0x0200);
+        assert_eq!(vm.registers, [0; 16]);
+        assert_eq!(vm.stack_pointer, 0);
+        assert_eq!(vm.delay_timer, 0);
+        assert!(!vm.is_key_pressed(0x5));
+        // The program and font are still there.
+        assert_eq!(&vm.memory[0x200..0x204], &[0x6E, 0x72, 0x23, 0x00]);
+        assert_eq!(&vm.memory[FONT_BASE..FONT_BASE + FONT.len()], &FONT[..]);
+    }
+
+    #[test]
+    fn step_returns_errors() {
+        let mut vm = Chip8::new();
+        // 0x00EE: Return, with nothing on the stack.
+        vm.memory[0x200..0x202].copy_from_slice(&[0x00, 0xEE]);
+        assert_eq!(vm.step(), Err(ExecError::StackUnderflow));
+
+        // Fetching the last byte of memory runs off the end.
+        vm.program_counter = (MEM_SIZE - 1) as u16;
+        assert_eq!(vm.step(), Err(ExecError::MemoryOutOfBounds { address: MEM_SIZE }));
+
+        // 0x8127: decodes, but isn't executed yet.
+        vm.program_counter = 0x200;
+        vm.memory[0x200..0x202].copy_from_slice(&[0x81, 0x27]);
+        assert_eq!(vm.step(), Err(ExecError::UnimplementedOpcode(Opcode::AltSubtractRegister { target: 0x1, other: 0x2 })));
+    }
+
+    #[test]
+    fn poke_opcode() {
+        let mut vm = Chip8::new();
+        vm.poke_opcode(0x200, &Opcode::SetRegister { register: 0x3, value: 0x72 }).unwrap();
+        assert_eq!(&vm.memory[0x200..0x202], &[0x63, 0x72]);
+        vm.step().unwrap();
+        assert_eq!(vm.registers[0x3], 0x72);
+
+        assert_eq!(vm.poke_opcode((MEM_SIZE - 1) as u16, &Opcode::ClearDisplay), Err(ExecError::MemoryOutOfBounds { address: MEM_SIZE }));
+    }
+
+    #[test]
+    fn run_steps_cycles() {
+        let mut vm = Chip8::new();
+        // 0x7001 (V0 += 1) over and over.
+        for address in (0x200..0x228).step_by(2) {
+            vm.memory[address..address + 2].copy_from_slice(&[0x70, 0x01]);
+        }
+
+        vm.run(7).unwrap();
+        assert_eq!(vm.registers[0], 7);
+        assert_eq!(vm.program_counter, 0x020E);
+    }
+
+    #[test]
+    fn run_stops_at_error() {
+        let mut vm = Chip8::new();
+        // V0 += 1 twice, then a Return with an empty stack.
+        vm.memory[0x200..0x208].copy_from_slice(&[0x70, 0x01, 0x70, 0x01, 0x00, 0xEE, 0x70, 0x01]);
+        assert_eq!(vm.run(10), Err(ExecError::StackUnderflow));
+        assert_eq!(vm.registers[0], 2);
+    }
+
+    #[test]
+    fn run_frame_ticks_timers_once() {
+        let mut vm = Chip8::new();
+        // 0x7001: V0 += 1; 0x1200: Jump to 0x200
+        vm.memory[0x200..0x204].copy_from_slice(&[0x70, 0x01, 0x12, 0x00]);
+        vm.delay_timer = 30;
+        vm.run_frame(10).unwrap();
+        assert_eq!(vm.registers[0], 5);
+        assert_eq!(vm.delay_timer, 29);
+    }
+
+    #[test]
+    fn step_reports_flow() {
+        let mut vm = Chip8::new();
+        // 0x6E72: SetRegister; 0x1208: Jump to 0x208
+        vm.memory[0x200..0x204].copy_from_slice(&[0x6E, 0x72, 0x12, 0x08]);
+        vm.step().unwrap();
+        assert_eq!(vm.last_flow(), ProgramFlow::Sequential);
+        vm.step().unwrap();
+        assert_eq!(vm.program_counter, 0x0208);
+        assert_eq!(vm.last_flow(), ProgramFlow::Branched);
+
+        // 0x00EE: Return with an empty stack fails, but still moved on sequentially.
+        vm.memory[0x208..0x20A].copy_from_slice(&[0x00, 0xEE]);
+        assert_eq!(vm.step(), Err(ExecError::StackUnderflow));
+        assert_eq!(vm.last_flow(), ProgramFlow::Sequential);
+    }
+
+    #[test]
+    fn pre_execute_hook() {
+        let mut vm = Chip8::new();
+        // 0x6E72: SetRegister VE = 0x72; 0x6172: SetRegister V1 = 0x72
+        vm.memory[0x200..0x204].copy_from_slice(&[0x6E, 0x72, 0x61, 0x72]);
+        vm.set_pre_execute_hook(Box::new(|opcode| {
+            match *opcode {
+                Opcode::SetRegister { register: 0xE, .. } => HookAction::Replace(Opcode::SetRegister { register: 0xE, value: 0x99 }),
+                Opcode::SetRegister { register: 0x1, .. } => HookAction::Skip,
+                _ => HookAction::Proceed,
+            }
+        }));
+
+        vm.step().unwrap();
+        assert_eq!(vm.registers[0xE], 0x99);
+        vm.step().unwrap();
+        assert_eq!(vm.registers[0x1], 0x00);
+        assert_eq!(vm.program_counter, 0x0204);
+    }
+
+    #[test]
+    fn pre_execute_hook_register_out_of_range() {
+        let mut vm = Chip8::new();
+        // 0x6E72: SetRegister VE = 0x72, replaced with one past VF.
+        vm.memory[0x200..0x202].copy_from_slice(&[0x6E, 0x72]);
+        vm.set_pre_execute_hook(Box::new(|_| HookAction::Replace(Opcode::SetRegister { register: 16, value: 0x99 })));
+
+        assert_eq!(vm.step(), Err(ExecError::RegisterOutOfRange { register: 16 }));
+        assert_eq!(vm.registers, [0; 16]);
+    }
+
+    #[test]
+    #[cfg(feature = "profiling")]
+    fn timing_breakdown() {
+        let mut vm = Chip8::new();
+        // 0x7001: V0 += 1; 0x1200: Jump to 0x200
+        vm.memory[0x200..0x204].copy_from_slice(&[0x70, 0x01, 0x12, 0x00]);
+        for _ in 0..10_000 {
+            vm.step().unwrap();
+        }
+
+        let (decode, execute) = vm.timing_breakdown();
+        assert!(decode > Duration::new(0, 0));
+        assert!(execute > Duration::new(0, 0));
+    }
+
+    #[test]
+    #[cfg(feature = "profiling")]
+    fn register_access_counts() {
+        let mut vm = Chip8::new();
+        // 0x6005: V0 = 0x05; 0x8010: V0 = V1
+        vm.memory[0x200..0x204].copy_from_slice(&[0x60, 0x05, 0x80, 0x10]);
+        vm.step().unwrap();
+        vm.step().unwrap();
+
+        let counts = vm.register_access_counts();
+        assert_eq!(counts[0x0], (0, 2));
+        assert_eq!(counts[0x1], (1, 0));
+        assert!(counts[0x2..].iter().all(|&count| count == (0, 0)));
+    }
+
+    #[test]
+    fn run_and_capture() {
+        let rom = [
+            0x60, 0x00, // V0 = 0
+            0x61, 0x00, // V1 = 0
+            0xF0, 0x29, // I = glyph for V0
+            0xD0, 0x15, // Draw 5 rows at (V0, V1)
+            0x12, 0x08, // Loop forever
+        ];
+        let glyph = [
+            "####",
+            "#..#",
+            "#..#",
+            "#..#",
+            "####",
+        ];
+
+        let mut expected = vec![false; GFX_SIZE_X * GFX_SIZE_Y];
+        for (y, row) in glyph.iter().enumerate() {
+            for (x, pixel) in row.chars().enumerate() {
+                expected[y * GFX_SIZE_X + x] = pixel == '#';
+            }
+        }
+
+        assert_eq!(Chip8::run_and_capture(&rom, 10), Ok(expected));
+
+        let too_large = vec![0x00; MEM_SIZE - 0x200 + 1];
+        assert_eq!(Chip8::run_and_capture(&too_large, 1), Err(ExecError::MemoryOutOfBounds { address: MEM_SIZE }));
+    }
+
+    #[test]
+    #[cfg(feature = "profiling")]
+    fn register_access_counts_with_quirks() {
+        let mut vm = Chip8::with_quirks(Quirks { index_overflow_sets_vf: true, ..Quirks::schip() });
+        // 0xE29E: skip if key V2 pressed; 0xF21E: I += V2; 0x8016: V0 >>= 1 (in place); 0xB300: JP V3, 0x300
+        vm.memory[0x200..0x208].copy_from_slice(&[0xE2, 0x9E, 0xF2, 0x1E, 0x80, 0x16, 0xB3, 0x00]);
+        vm.run(4).unwrap();
+
+        let counts = vm.register_access_counts();
+        assert_eq!(counts[0x2], (2, 0));
+        // SHR reads V0 rather than V1 under the quirk; its write and Fx1E's are the only VF writes.
+        assert_eq!(counts[0x0], (1, 1));
+        assert_eq!(counts[0x1], (0, 0));
+        assert_eq!(counts[0x3], (1, 0));
+        assert_eq!(counts[0xF], (0, 2));
+    }
+
+    #[test]
+    fn new_loads_font() {
+        let vm = Chip8::new();
+        assert_eq!(&vm.memory[FONT_BASE..FONT_BASE + FONT.len()], &FONT[..]);
+    }
+
+    #[test]
+    fn schip_quirks() {
+        let mut cosmac = Chip8::new();
+        let mut schip = Chip8::with_quirks(Quirks::schip());
+        assert_eq!(cosmac.quirks, Quirks::default());
+        assert_ne!(schip.quirks, Quirks::default());
+
+        for vm in [&mut cosmac, &mut schip].iter_mut() {
+            vm.registers[0] = 0b0000_0100;
+            vm.registers[1] = 0b0000_0011;
+            vm.execute_opcode(Opcode::RightShift { target: 0, source: 1 }).unwrap();
+        }
+
+        // COSMAC shifts V1 into V0; SCHIP shifts V0 in place.
+        assert_eq!((cosmac.registers[0], cosmac.registers[0xF]), (0b0000_0001, 1));
+        assert_eq!((schip.registers[0], schip.registers[0xF]), (0b0000_0010, 0));
+    }
+
+    #[test]
+    fn save_and_load_state() {
+        let mut vm = Chip8::new();
+        // V0 = 0x05; I = glyph for V0; draw it; call 0x300, which sets V1 = 0x07
+        vm.memory[0x200..0x208].copy_from_slice(&[0x60, 0x05, 0xF0, 0x29, 0xD0, 0x05, 0x23, 0x00]);
+        vm.memory[0x300..0x302].copy_from_slice(&[0x61, 0x07]);
+        vm.run(5).unwrap();
+        vm.delay_timer = 12;
+        vm.sound_timer = 34;
+        vm.set_key(0x5, true);
+        let saved = vm.save_state();
+
+        let memory = vm.memory;
+        let registers = vm.registers;
+        let gfx_memory = vm.gfx_memory;
+        let stack = vm.stack;
+
+        // Scribble over everything the state covers.
+        vm.memory = [0xAA; MEM_SIZE];
+        vm.gfx_memory = [true; GFX_SIZE_X * GFX_SIZE_Y];
+        vm.reset();
+        vm.stack = [0xBEEF; STACK_SIZE];
+
+        vm.load_state(&saved).unwrap();
+        assert_eq!(&vm.memory[..], &memory[..]);
+        assert_eq!(vm.registers, registers);
+        assert_eq!(vm.registers[0x1], 0x07);
+        assert_eq!(vm.index_register, 0x050 + 0x5 * 5);
+        assert_eq!(vm.program_counter, 0x0302);
+        assert_eq!(&vm.gfx_memory[..], &gfx_memory[..]);
+        assert!(vm.gfx_memory.iter().any(|&pixel| pixel));
+        assert_eq!(vm.delay_timer, 12);
+        assert_eq!(vm.sound_timer, 34);
+        assert_eq!(vm.stack, stack);
+        assert_eq!(vm.stack_pointer, 1);
+        assert!(vm.is_key_pressed(0x5));
+        assert_eq!(vm.save_state(), saved);
+    }
+
+    #[test]
+    fn load_state_rejects_bad_bytes() {
+        let mut vm = Chip8::new();
+        match vm.load_state(&[0x01, 0x02, 0x03]) {
+            Err(StateError::Malformed(_)) => {},
+            other => panic!("expected a malformed state error, got {:?}", other),
+        }
+        assert_eq!(vm.program_counter, 0x0200);
+    }
+
+    #[test]
+    fn load_state_rejects_invalid_state() {
+        let mut vm = Chip8::new();
+        let corrupt = |change: &dyn Fn(&mut SavedState)| {
+            let mut state: SavedState = bincode::deserialize(&Chip8::new().save_state()).unwrap();
+            change(&mut state);
+            bincode::serialize(&state).unwrap()
+        };
+
+        for bytes in &[corrupt(&|state| state.stack_pointer = 200), corrupt(&|state| state.index_register = 0xFFFF)] {
+            match vm.load_state(bytes) {
+                Err(StateError::InvalidState) => {},
+                other => panic!("expected an invalid state error, got {:?}", other),
+            }
+        }
+
+        assert_eq!(vm.stack_pointer, 0);
+        assert_eq!(vm.index_register, 0);
+    }
+
+    #[test]
+    fn gfx_covers_screen() {
+        let vm = Chip8::new();
+        assert_eq!(vm.gfx().len(), GFX_SIZE_X * GFX_SIZE_Y);
+    }
+
+    #[test]
+    fn framebuffer_bit_order() {
+        let mut vm = Chip8::new();
+        vm.gfx_memory[0] = true;
+        vm.gfx_memory[1] = true;
+        vm.gfx_memory[12] = true;
+
+        let msb = vm.framebuffer_with_bit_order(true);
+        let lsb = vm.framebuffer_with_bit_order(false);
+        assert_eq!(msb.len(), GFX_SIZE_X * GFX_SIZE_Y / 8);
+        assert_eq!(lsb.len(), GFX_SIZE_X * GFX_SIZE_Y / 8);
+        assert_eq!(&msb[0..2], &[0b1100_0000, 0b0000_1000]);
+        assert_eq!(&lsb[0..2], &[0b0000_0011, 0b0001_0000]);
+    }
+
+    #[test]
+    fn framebuffer_bytes() {
+        let mut vm = Chip8::new();
+        // Top-left corner, a pixel straddling the first byte boundary, and the bottom-right corner.
+        vm.gfx_memory[0] = true;
+        vm.gfx_memory[7] = true;
+        vm.gfx_memory[8] = true;
+        vm.gfx_memory[GFX_SIZE_X + 3] = true;
+        vm.gfx_memory[GFX_SIZE_X * GFX_SIZE_Y - 1] = true;
+
+        let bytes = vm.framebuffer_bytes();
+        assert_eq!(bytes.len(), 256);
+        assert_eq!(&bytes[0..2], &[0b1000_0001, 0b1000_0000]);
+        // Row 1 starts 8 bytes in.
+        assert_eq!(bytes[8], 0b0001_0000);
+        assert_eq!(bytes[255], 0b0000_0001);
+        assert_eq!(bytes.iter().map(|byte| byte.count_ones()).sum::<u32>(), 5);
+
+        assert!(vm.pixel(3, 1));
+        assert!(vm.pixel(GFX_SIZE_X - 1, GFX_SIZE_Y - 1));
+        assert!(!vm.pixel(4, 1));
+    }
+
+    #[test]
+    fn dirty_tracking() {
+        let mut vm = Chip8::new();
+        assert_eq!(vm.take_dirty(), None);
+
+        vm.memory[0x300..0x302].copy_from_slice(&[0b1100_0000, 0b0100_0000]);
+        vm.index_register = 0x300;
+        vm.registers[0] = 10;
+        vm.registers[1] = 5;
+        vm.execute_opcode(Opcode::Display { x: 0, y: 1, height: 2 }).unwrap();
+        assert_eq!(vm.take_dirty(), Some(Rect { x: 10, y: 5, width: 2, height: 2 }));
+        assert_eq!(vm.take_dirty(), None);
+
+        // Two draws accumulate into one region.
+        vm.execute_opcode(Opcode::Display { x: 0, y: 1, height: 2 }).unwrap();
+        vm.registers[0] = 20;
+        vm.registers[1] = 8;
+        vm.execute_opcode(Opcode::Display { x: 0, y: 1, height: 1 }).unwrap();
+        assert_eq!(vm.take_dirty(), Some(Rect { x: 10, y: 5, width: 12, height: 4 }));
+
+        // Clearing a blank screen changes nothing; clearing a drawn one changes everything.
+        vm.clear_display();
+        assert_eq!(vm.take_dirty(), Some(Rect { x: 0, y: 0, width: GFX_SIZE_X, height: GFX_SIZE_Y }));
+        vm.execute_opcode(Opcode::ClearDisplay).unwrap();
+        assert_eq!(vm.take_dirty(), None);
+    }
+
+    #[test]
+    fn lists_supported_opcodes() {
+        let vm = Chip8::new();
+        let supported = vm.supported_opcodes();
+        for pattern in &["1NNN", "3XNN", "6XNN", "8XY4", "ANNN"] {
+            assert!(supported.contains(pattern), "expected {} to be supported", pattern);
+        }
+
+        // SCHIP extensions aren't part of the classic instruction set.
+        for pattern in &["00CN", "00FB", "00FC", "00FD", "00FE", "00FF", "FX30", "FX75", "FX85"] {
+            assert!(!supported.contains(pattern), "expected {} to be unsupported", pattern);
+        }
+    }
+
+    #[test]
+    fn supported_opcodes_match_executor() {
+        let supported = Chip8::new().supported_opcodes();
+        let executes = |word: u16| match decode_opcode(word) {
+            Some(opcode) => Chip8::new().execute_opcode(opcode) != Err(ExecError::UnimplementedOpcode(opcode)),
+            None => false,
+        };
+        // X, Y, and N stand for any nibble; hex digits must match exactly.
+        let matches = |pattern: &str, word: u16| pattern.chars().enumerate().all(|(i, c)| {
+            let nibble = (word >> (12 - 4 * i)) & 0xF;
+            c == 'X' || c == 'Y' || c == 'N' || c.to_digit(16) == Some(nibble as u32)
+        });
+
+        for pattern in &supported {
+            let word = u16::from_str_radix(&pattern.replace('X', "1").replace('Y', "2").replace('N', "3"), 16).unwrap();
+            assert!(executes(word), "{} is listed but {:#06X} doesn't execute", pattern, word);
+        }
+
+        for word in 0..=0xFFFF {
+            if executes(word) {
+                assert!(supported.iter().any(|pattern| matches(pattern, word)), "{:#06X} executes but isn't listed", word);
+            }
+        }
+    }
+
+    mod opcode_executing {
+        use super::*;
+
+        #[test]
+        fn jump() {
+            let mut vm = Chip8::new();
+            vm.execute_opcode(Opcode::Jump { address: 0x09DE }).unwrap();
+            assert_eq!(vm.program_counter, 0x09DE);
+        }
+
+        #[test]
+        fn clear_display() {
+            let mut vm = Chip8::new();
+            vm.gfx_memory[0] = true;
+            vm.gfx_memory[100] = true;
+            vm.gfx_memory[GFX_SIZE_X * GFX_SIZE_Y - 1] = true;
+            vm.execute_opcode(Opcode::ClearDisplay).unwrap();
+            assert!(vm.gfx_memory.iter().all(|&pixel| !pixel));
+        }
+
+        #[test]
+        fn nested_call_and_return() {
+            let mut vm = Chip8::new();
+            vm.program_counter = 0x0202;
+            vm.execute_opcode(Opcode::Call { address: 0x0300 }).unwrap();
+            vm.program_counter += 2;
+            vm.execute_opcode(Opcode::Call { address: 0x0400 }).unwrap();
+            vm.program_counter += 2;
+            vm.execute_opcode(Opcode::Call { address: 0x0500 }).unwrap();
+            assert_eq!(vm.program_counter, 0x0500);
+            assert_eq!(vm.stack_pointer, 3);
+            assert_eq!(&vm.stack[0..3], &[0x0202, 0x0302, 0x0402]);
+
+            vm.execute_opcode(Opcode::Return).unwrap();
+            assert_eq!(vm.program_counter, 0x0402);
+            vm.execute_opcode(Opcode::Return).unwrap();
+            assert_eq!(vm.program_counter, 0x0302);
+            vm.execute_opcode(Opcode::Return).unwrap();
+            assert_eq!(vm.program_counter, 0x0202);
+            assert_eq!(vm.stack_pointer, 0);
+        }
+
+        #[test]
+        fn call_overflow() {
+            let mut vm = Chip8::new();
+            for _ in 0..STACK_SIZE {
+                vm.execute_opcode(Opcode::Call { address: 0x0300 }).unwrap();
+            }
+
+            assert_eq!(vm.execute_opcode(Opcode::Call { address: 0x0300 }), Err(ExecError::StackOverflow));
+            assert_eq!(vm.stack_pointer as usize, STACK_SIZE);
+        }
+
+        #[test]
+        fn return_underflow() {
+            let mut vm = Chip8::new();
+            assert_eq!(vm.execute_opcode(Opcode::Return), Err(ExecError::StackUnderflow));
+            assert_eq!(vm.stack_pointer, 0);
+        }
+
+        #[test]
+        fn set_idx_reg() {
+            let mut vm = Chip8::new();
+            vm.execute_opcode(Opcode::SetIndexRegister { value: 0x0387 }).unwrap();
+            assert_eq!(vm.index_register, 0x0387);
+        }
+
+        #[test]
+        fn skip_if_eq_const() {
+            let mut vm = Chip8::new();
+            vm.execute_opcode(Opcode::SkipIfEqual { register: 0xA, value: 0x32 }).unwrap();
+            // Scenario 1: register A is 0, but we expect 0x32.
+            // This will not skip the next instruction. The program
+            // counter can thus be expected to be 0x0200.
+            assert_eq!(vm.program_counter, 0x0200);
+            
+            // Reset the program counter.
+            vm.program_counter = 0x0200;
+            // Scenario 2: register A is now 0x32, and we expect
+            // 0x32. This *will* skip the next instruction. The
+            // program counter should be 0x0202.
+            vm.registers[0x0A] = 0x32;
+            vm.execute_opcode(Opcode::SkipIfEqual { register: 0xA, value: 0x32 }).unwrap();
+            assert_eq!(vm.program_counter, 0x0202);
+        }
+
+        #[test]
+        fn skip_if_not_eq_const() {
+            // This test is the reverse of skip_if_eq_const.
+            let mut vm = Chip8::new();
+            vm.execute_opcode(Opcode::SkipIfNotEqual { register: 0xA, value: 0x32 }).unwrap();
+            // Scenario 1: register A is 0, but we expect 0x32.
+            // This will skip the next instruction. The program
+            // counter can thus be expected to be 0x0202.
+            assert_eq!(vm.program_counter, 0x0202);
+            
+            // Reset the program counter.
+            vm.program_counter = 0x0200;
+            // Scenario 2: register A is now 0x32, and we expect
+            // 0x32. This will not skip the next instruction. The
+            // program counter should be 0x0200.
+            vm.registers[0x0A] = 0x32;
+            vm.execute_opcode(Opcode::SkipIfNotEqual { register: 0xA, value: 0x32 }).unwrap();
+            assert_eq!(vm.program_counter, 0x0200);
         }
 
         #[test]
@@ -597,26 +2077,71 @@ mod test {
             let mut vm = Chip8::new();
             vm.registers[0xA] = 0x0;
             vm.registers[0xB] = 0xF;
-            vm.execute_opcode(Opcode::SkipIfRegistersEqual { register1: 0xA, register2: 0xB });
+            vm.execute_opcode(Opcode::SkipIfRegistersEqual { register1: 0xA, register2: 0xB }).unwrap();
             // Scenario 1: register A is 0 and register B is 0x0F.
             // The next instruction should not be skipped; program_counter
-            // should be 0x0000.
-            assert_eq!(vm.program_counter, 0x0000);
+            // should be 0x0200.
+            assert_eq!(vm.program_counter, 0x0200);
             
             // Reset the program counter.
-            vm.program_counter = 0x0000;
+            vm.program_counter = 0x0200;
             // Scenario 2: register A is now 0x0F, the same as
             // register B. This *will* skip the next instruction - the
-            // program counter should be 0x0002.
+            // program counter should be 0x0202.
+            vm.registers[0xA] = 0x0F;
+            vm.execute_opcode(Opcode::SkipIfRegistersEqual { register1: 0xA, register2: 0xB }).unwrap();
+            assert_eq!(vm.program_counter, 0x0202);
+        }
+
+        #[test]
+        fn skip_over_four_byte_instruction() {
+            let mut vm = Chip8::new();
+            // 0x6E72 is a two-byte instruction, so the skip moves past two bytes.
+            vm.memory[0x200..0x202].copy_from_slice(&[0x6E, 0x72]);
+            vm.program_counter = 0x0200;
+            vm.execute_opcode(Opcode::SkipIfEqual { register: 0xA, value: 0x00 }).unwrap();
+            assert_eq!(vm.program_counter, 0x0202);
+
+            // 0xF000 0x1234 is a four-byte long load; the whole thing is skipped.
+            vm.memory[0x300..0x304].copy_from_slice(&[0xF0, 0x00, 0x12, 0x34]);
+            vm.program_counter = 0x0300;
+            vm.execute_opcode(Opcode::SkipIfEqual { register: 0xA, value: 0x00 }).unwrap();
+            assert_eq!(vm.program_counter, 0x0304);
+        }
+
+        #[test]
+        fn skip_if_registers_not_eq() {
+            let mut vm = Chip8::new();
             vm.registers[0xA] = 0x0F;
-            vm.execute_opcode(Opcode::SkipIfRegistersEqual { register1: 0xA, register2: 0xB });
-            assert_eq!(vm.program_counter, 0x0002);
+            vm.registers[0xB] = 0x0F;
+            vm.execute_opcode(Opcode::SkipIfRegistersNotEqual { register1: 0xA, register2: 0xB }).unwrap();
+            // Scenario 1: both registers are 0x0F, so nothing is skipped.
+            assert_eq!(vm.program_counter, 0x0200);
+
+            // Scenario 2: register A is now 0x00, so the next instruction is skipped.
+            vm.registers[0xA] = 0x00;
+            vm.execute_opcode(Opcode::SkipIfRegistersNotEqual { register1: 0xA, register2: 0xB }).unwrap();
+            assert_eq!(vm.program_counter, 0x0202);
+        }
+
+        #[test]
+        fn offset_jump() {
+            let mut vm = Chip8::new();
+            vm.registers[0] = 0x10;
+            vm.registers[3] = 0x20;
+            vm.execute_opcode(Opcode::OffsetJump { address: 0x0300 }).unwrap();
+            assert_eq!(vm.program_counter, 0x0310);
+
+            // Under the Bxnn quirk, 0x300 jumps to 0x300 + V3.
+            vm.quirks.jump_uses_vx = true;
+            vm.execute_opcode(Opcode::OffsetJump { address: 0x0300 }).unwrap();
+            assert_eq!(vm.program_counter, 0x0320);
         }
 
         #[test]
         fn set_register() {
             let mut vm = Chip8::new();
-            vm.execute_opcode(Opcode::SetRegister { register: 0x0, value: 0xFF });
+            vm.execute_opcode(Opcode::SetRegister { register: 0x0, value: 0xFF }).unwrap();
             assert_eq!(vm.registers[0], 0xFF);
         }
 
@@ -624,7 +2149,7 @@ mod test {
         fn add_const() {
             let mut vm = Chip8::new();
             vm.registers[0] = 0x13;
-            vm.execute_opcode(Opcode::AddConstant { register: 0, value: 0x23 });
+            vm.execute_opcode(Opcode::AddConstant { register: 0, value: 0x23 }).unwrap();
             assert_eq!(vm.registers[0], 0x23 + 0x13);
         }
 
@@ -633,8 +2158,23 @@ mod test {
             let mut vm = Chip8::new();
             vm.registers[0] = 0x13;
             vm.registers[1] = 0xFF;
-            vm.execute_opcode(Opcode::CopyRegister { source: 1, target: 0 });
+            vm.execute_opcode(Opcode::CopyRegister { source: 1, target: 0 }).unwrap();
+            assert_eq!(vm.registers[0], 0xFF);
+        }
+
+        #[test]
+        fn copy_register_vf_quirk() {
+            let mut vm = Chip8::new();
+            vm.registers[1] = 0xFF;
+            vm.registers[0xF] = 0x01;
+            vm.execute_opcode(Opcode::CopyRegister { source: 1, target: 0 }).unwrap();
+            // By default, VF is left alone.
+            assert_eq!(vm.registers[0xF], 0x01);
+
+            vm.quirks.copy_resets_vf = true;
+            vm.execute_opcode(Opcode::CopyRegister { source: 1, target: 0 }).unwrap();
             assert_eq!(vm.registers[0], 0xFF);
+            assert_eq!(vm.registers[0xF], 0x00);
         }
 
         #[test]
@@ -642,7 +2182,7 @@ mod test {
             let mut vm = Chip8::new();
             vm.registers[0] = 0x13;
             vm.registers[1] = 0xC4;
-            vm.execute_opcode(Opcode::BitOr { target: 0, other: 1 });
+            vm.execute_opcode(Opcode::BitOr { target: 0, other: 1 }).unwrap();
             assert_eq!(vm.registers[0], 0x13 | 0xC4);
         }
 
@@ -651,7 +2191,7 @@ mod test {
             let mut vm = Chip8::new();
             vm.registers[0] = 0x13;
             vm.registers[1] = 0xC4;
-            vm.execute_opcode(Opcode::BitAnd { target: 0, other: 1 });
+            vm.execute_opcode(Opcode::BitAnd { target: 0, other: 1 }).unwrap();
             assert_eq!(vm.registers[0], 0x13 & 0xC4);
         }
 
@@ -660,10 +2200,32 @@ mod test {
             let mut vm = Chip8::new();
             vm.registers[0] = 0x13;
             vm.registers[1] = 0xC4;
-            vm.execute_opcode(Opcode::BitXor { target: 0, other: 1 });
+            vm.execute_opcode(Opcode::BitXor { target: 0, other: 1 }).unwrap();
             assert_eq!(vm.registers[0], 0x13 ^ 0xC4);
         }
 
+        #[test]
+        fn logic_vf_quirk() {
+            let logic_opcodes = [
+                Opcode::BitOr { target: 0, other: 1 },
+                Opcode::BitAnd { target: 0, other: 1 },
+                Opcode::BitXor { target: 0, other: 1 },
+            ];
+
+            for opcode in logic_opcodes.iter() {
+                let mut vm = Chip8::new();
+                vm.registers[0xF] = 0x01;
+                vm.execute_opcode(*opcode).unwrap();
+                // The quirk is on by default.
+                assert_eq!(vm.registers[0xF], 0x00, "{} didn't reset VF", opcode);
+
+                vm.quirks.logic_resets_vf = false;
+                vm.registers[0xF] = 0x01;
+                vm.execute_opcode(*opcode).unwrap();
+                assert_eq!(vm.registers[0xF], 0x01, "{} reset VF", opcode);
+            }
+        }
+
         #[test]
         fn register_add() {
             let mut vm = Chip8::new();
@@ -671,10 +2233,10 @@ mod test {
             vm.registers[1] = 0xC4;
             vm.registers[2] = 0xFF;
             vm.registers[3] = 0xD9;
-            vm.execute_opcode(Opcode::AddRegister { target: 0, other: 1 });
+            vm.execute_opcode(Opcode::AddRegister { target: 0, other: 1 }).unwrap();
             assert_eq!(vm.registers[0], 0x13 + 0xC4);
             assert_eq!(vm.registers[0xF], 0);
-            vm.execute_opcode(Opcode::AddRegister { target: 2, other: 3 });
+            vm.execute_opcode(Opcode::AddRegister { target: 2, other: 3 }).unwrap();
             assert_eq!(vm.registers[2], 0xD8);
             assert_eq!(vm.registers[0xF], 1);
         }
@@ -686,13 +2248,364 @@ mod test {
             vm.registers[1] = 0xC4;
             vm.registers[2] = 0x13;
             vm.registers[3] = 0x11;
-            vm.execute_opcode(Opcode::SubtractRegister { target: 0, other: 1 });
+            vm.execute_opcode(Opcode::SubtractRegister { target: 0, other: 1 }).unwrap();
             assert_eq!(vm.registers[0], 0x4F);
             assert_eq!(vm.registers[0xF], 1);
-            vm.execute_opcode(Opcode::SubtractRegister { target: 2, other: 3 });
+            vm.execute_opcode(Opcode::SubtractRegister { target: 2, other: 3 }).unwrap();
             assert_eq!(vm.registers[2], 0x02);
             assert_eq!(vm.registers[0xF], 0);
         }
+
+        #[test]
+        fn skip_if_key_pressed() {
+            let mut vm = Chip8::new();
+            vm.registers[0x3] = 0x5;
+            vm.execute_opcode(Opcode::SkipIfKeyPressed { key: 0x3 }).unwrap();
+            // Scenario 1: key 5 is up; nothing is skipped.
+            assert_eq!(vm.program_counter, 0x0200);
+
+            // Scenario 2: key 5 is down; the next instruction is skipped.
+            vm.set_key(0x5, true);
+            vm.execute_opcode(Opcode::SkipIfKeyPressed { key: 0x3 }).unwrap();
+            assert_eq!(vm.program_counter, 0x0202);
+        }
+
+        #[test]
+        fn skip_if_key_not_pressed() {
+            let mut vm = Chip8::new();
+            vm.registers[0x3] = 0x5;
+            vm.set_key(0x5, true);
+            vm.execute_opcode(Opcode::SkipIfKeyNotPressed { key: 0x3 }).unwrap();
+            // Scenario 1: key 5 is down; nothing is skipped.
+            assert_eq!(vm.program_counter, 0x0200);
+
+            // Scenario 2: key 5 is released; the next instruction is skipped.
+            vm.set_key(0x5, false);
+            assert!(!vm.is_key_pressed(0x5));
+            vm.execute_opcode(Opcode::SkipIfKeyNotPressed { key: 0x3 }).unwrap();
+            assert_eq!(vm.program_counter, 0x0202);
+        }
+
+        #[test]
+        fn skip_if_key_pressed_out_of_range() {
+            let mut vm = Chip8::new();
+            vm.registers[0x3] = 0x10;
+            assert_eq!(vm.execute_opcode(Opcode::SkipIfKeyPressed { key: 0x3 }), Err(ExecError::KeyOutOfRange { key: 0x10 }));
+        }
+
+        #[test]
+        fn increment_index_register() {
+            let mut vm = Chip8::new();
+            vm.index_register = 0x0300;
+            vm.registers[2] = 0x42;
+            vm.registers[0xF] = 0x07;
+            vm.execute_opcode(Opcode::IncrementIndexRegister { register: 2 }).unwrap();
+            assert_eq!(vm.index_register, 0x0342);
+            assert_eq!(vm.registers[0xF], 0x07);
+        }
+
+        #[test]
+        fn increment_index_register_overflow() {
+            for &sets_vf in &[false, true] {
+                let mut vm = Chip8::new();
+                vm.quirks.index_overflow_sets_vf = sets_vf;
+                vm.index_register = 0x0FF0;
+                vm.registers[2] = 0x20;
+                vm.registers[0xF] = 0x07;
+                vm.execute_opcode(Opcode::IncrementIndexRegister { register: 2 }).unwrap();
+                assert_eq!(vm.index_register, 0x0010);
+                assert_eq!(vm.registers[0xF], if sets_vf { 1 } else { 0x07 });
+
+                // A normal increment clears VF under the quirk.
+                vm.execute_opcode(Opcode::IncrementIndexRegister { register: 2 }).unwrap();
+                assert_eq!(vm.index_register, 0x0030);
+                assert_eq!(vm.registers[0xF], if sets_vf { 0 } else { 0x07 });
+            }
+        }
+
+        #[test]
+        fn set_index_to_font() {
+            let mut vm = Chip8::new();
+            vm.registers[3] = 0xA;
+            vm.execute_opcode(Opcode::SetIndexToFont { register: 3 }).unwrap();
+            assert_eq!(vm.index_register, 0x050 + 0xA * 5);
+
+            let start = vm.index_register as usize;
+            assert_eq!(&vm.memory[start..start + 5], &[0xF0, 0x90, 0xF0, 0x90, 0x90]);
+        }
+
+        #[test]
+        fn right_shift() {
+            let mut vm = Chip8::new();
+            vm.registers[0] = 0b1000_0000;
+            vm.registers[1] = 0b0000_0011;
+            vm.execute_opcode(Opcode::RightShift { target: 0, source: 1 }).unwrap();
+            assert_eq!(vm.registers[0], 0b0000_0001);
+            assert_eq!(vm.registers[0xF], 1);
+
+            vm.quirks.shift_in_place = true;
+            vm.registers[0] = 0b1000_0000;
+            vm.execute_opcode(Opcode::RightShift { target: 0, source: 1 }).unwrap();
+            assert_eq!(vm.registers[0], 0b0100_0000);
+            assert_eq!(vm.registers[0xF], 0);
+        }
+
+        #[test]
+        fn left_shift() {
+            let mut vm = Chip8::new();
+            vm.registers[0] = 0b0000_0001;
+            vm.registers[1] = 0b1100_0000;
+            vm.execute_opcode(Opcode::LeftShift { target: 0, source: 1 }).unwrap();
+            assert_eq!(vm.registers[0], 0b1000_0000);
+            assert_eq!(vm.registers[0xF], 1);
+
+            vm.quirks.shift_in_place = true;
+            vm.registers[0] = 0b0000_0001;
+            vm.execute_opcode(Opcode::LeftShift { target: 0, source: 1 }).unwrap();
+            assert_eq!(vm.registers[0], 0b0000_0010);
+            assert_eq!(vm.registers[0xF], 0);
+        }
+
+        #[test]
+        fn store_decimal() {
+            let mut vm = Chip8::new();
+            vm.index_register = 0x300;
+
+            vm.registers[0] = 0;
+            vm.execute_opcode(Opcode::StoreDecimal { register: 0 }).unwrap();
+            assert_eq!(&vm.memory[0x300..0x303], &[0, 0, 0]);
+
+            vm.registers[0] = 255;
+            vm.execute_opcode(Opcode::StoreDecimal { register: 0 }).unwrap();
+            assert_eq!(&vm.memory[0x300..0x303], &[2, 5, 5]);
+
+            vm.registers[0] = 107;
+            vm.execute_opcode(Opcode::StoreDecimal { register: 0 }).unwrap();
+            assert_eq!(&vm.memory[0x300..0x303], &[1, 0, 7]);
+        }
+
+        #[test]
+        fn store_decimal_out_of_bounds() {
+            let mut vm = Chip8::new();
+            vm.index_register = (MEM_SIZE - 2) as u16;
+            assert_eq!(vm.execute_opcode(Opcode::StoreDecimal { register: 0 }), Err(ExecError::MemoryOutOfBounds { address: MEM_SIZE }));
+        }
+
+        #[test]
+        fn mem_dump_and_load() {
+            for &increments_index in &[false, true] {
+                let mut vm = Chip8::new();
+                vm.quirks.memory_increments_index = increments_index;
+                let expected = [0x01, 0x23, 0x45, 0x67, 0x89, 0xAB];
+                vm.registers[0..6].copy_from_slice(&expected);
+                vm.registers[6] = 0xCD;
+                vm.index_register = 0x300;
+
+                vm.execute_opcode(Opcode::MemDump { max_register: 5 }).unwrap();
+                assert_eq!(&vm.memory[0x300..0x306], &expected);
+                // Only V0-V5 are dumped.
+                assert_eq!(vm.memory[0x306], 0);
+                assert_eq!(vm.index_register, if increments_index { 0x306 } else { 0x300 });
+
+                vm.registers = [0; 16];
+                vm.index_register = 0x300;
+                vm.execute_opcode(Opcode::MemLoad { max_register: 5 }).unwrap();
+                assert_eq!(&vm.registers[0..6], &expected);
+                assert_eq!(vm.registers[6], 0);
+                assert_eq!(vm.index_register, if increments_index { 0x306 } else { 0x300 });
+            }
+        }
+
+        #[test]
+        fn mem_dump_and_load_wrap_index() {
+            let mut vm = Chip8::new();
+            vm.quirks.memory_increments_index = true;
+            vm.index_register = 0xFFE;
+            vm.execute_opcode(Opcode::MemDump { max_register: 1 }).unwrap();
+            assert_eq!(vm.index_register, 0x000);
+
+            vm.index_register = 0xFFD;
+            vm.execute_opcode(Opcode::MemLoad { max_register: 1 }).unwrap();
+            assert_eq!(vm.index_register, 0xFFF);
+        }
+
+        #[test]
+        fn rand() {
+            let mut vm = Chip8::new();
+            vm.set_random_source(Box::new(|| 0xFF));
+            vm.execute_opcode(Opcode::Rand { mask: 0x3C, register: 2 }).unwrap();
+            assert_eq!(vm.registers[2], 0x3C);
+
+            let mut next = 0u8;
+            vm.set_random_source(Box::new(move || {
+                next = next.wrapping_add(0x11);
+                next
+            }));
+            vm.execute_opcode(Opcode::Rand { mask: 0xF0, register: 2 }).unwrap();
+            assert_eq!(vm.registers[2], 0x10);
+            vm.execute_opcode(Opcode::Rand { mask: 0xF0, register: 2 }).unwrap();
+            assert_eq!(vm.registers[2], 0x20);
+        }
+
+        #[test]
+        fn display() {
+            let mut vm = Chip8::new();
+            vm.memory[0x300..0x302].copy_from_slice(&[0b1100_0000, 0b1000_0001]);
+            vm.index_register = 0x300;
+            vm.registers[0] = 2;
+            vm.registers[1] = 3;
+            vm.execute_opcode(Opcode::Display { x: 0, y: 1, height: 2 }).unwrap();
+            assert!(vm.gfx_memory[3 * GFX_SIZE_X + 2]);
+            assert!(vm.gfx_memory[3 * GFX_SIZE_X + 3]);
+            assert!(!vm.gfx_memory[3 * GFX_SIZE_X + 4]);
+            assert!(vm.gfx_memory[4 * GFX_SIZE_X + 2]);
+            assert!(vm.gfx_memory[4 * GFX_SIZE_X + 9]);
+            assert_eq!(vm.gfx_memory.iter().filter(|&&pixel| pixel).count(), 4);
+            assert_eq!(vm.registers[0xF], 0);
+        }
+
+        #[test]
+        fn display_collision() {
+            let mut vm = Chip8::new();
+            vm.memory[0x300] = 0b1111_0000;
+            vm.index_register = 0x300;
+            vm.registers[0] = 0;
+            vm.registers[1] = 0;
+            vm.execute_opcode(Opcode::Display { x: 0, y: 1, height: 1 }).unwrap();
+            assert_eq!(vm.registers[0xF], 0);
+
+            // Overlap the second sprite with the right half of the first.
+            vm.registers[0] = 2;
+            vm.execute_opcode(Opcode::Display { x: 0, y: 1, height: 1 }).unwrap();
+            assert_eq!(vm.registers[0xF], 1);
+            assert_eq!(&vm.gfx_memory[0..7], &[true, true, false, false, true, true, false]);
+
+            // Drawing onto blank pixels clears the flag again.
+            vm.registers[1] = 10;
+            vm.execute_opcode(Opcode::Display { x: 0, y: 1, height: 1 }).unwrap();
+            assert_eq!(vm.registers[0xF], 0);
+        }
+
+        #[test]
+        fn display_wraps_origin_and_clips_sprite() {
+            let mut vm = Chip8::new();
+            vm.memory[0x300..0x302].copy_from_slice(&[0xFF, 0xFF]);
+            vm.index_register = 0x300;
+            // (124, 63) wraps around to (60, 31).
+            vm.registers[0] = 124;
+            vm.registers[1] = 63;
+            vm.execute_opcode(Opcode::Display { x: 0, y: 1, height: 2 }).unwrap();
+            // Only four pixels of the first row fit; the second row is off the bottom.
+            assert_eq!(&vm.gfx_memory[31 * GFX_SIZE_X + 60..], &[true; 4]);
+            assert_eq!(vm.gfx_memory.iter().filter(|&&pixel| pixel).count(), 4);
+        }
+
+        #[test]
+        fn display_tall_sprite_clips_at_bottom() {
+            let mut vm = Chip8::new();
+            for row in 0..15 {
+                vm.memory[0x300 + row] = 0x80 >> (row % 8);
+            }
+            vm.index_register = 0x300;
+            vm.registers[0] = 0;
+            vm.registers[1] = 30;
+            vm.execute_opcode(Opcode::Display { x: 0, y: 1, height: 15 }).unwrap();
+            // Only the first two rows are on screen; the other 13 are clipped.
+            assert!(vm.gfx_memory[30 * GFX_SIZE_X]);
+            assert!(vm.gfx_memory[31 * GFX_SIZE_X + 1]);
+            assert_eq!(vm.gfx_memory.iter().filter(|&&pixel| pixel).count(), 2);
+            assert_eq!(vm.registers[0xF], 0);
+        }
+
+        #[test]
+        fn display_reports_clipped_rows() {
+            let mut vm = Chip8::new();
+            vm.index_register = 0x300;
+            vm.registers[0] = 0;
+            vm.registers[1] = 28;
+            vm.execute_opcode(Opcode::Display { x: 0, y: 1, height: 8 }).unwrap();
+            assert_eq!(vm.last_draw_rows(), 4);
+
+            vm.registers[1] = 0;
+            vm.execute_opcode(Opcode::Display { x: 0, y: 1, height: 8 }).unwrap();
+            assert_eq!(vm.last_draw_rows(), 8);
+        }
+
+        #[test]
+        fn display_out_of_bounds_draws_nothing() {
+            let mut vm = Chip8::new();
+            vm.memory[0xFFF] = 0xFF;
+            vm.index_register = 0xFFF;
+            vm.registers[0xF] = 0x42;
+            vm.take_dirty();
+            assert_eq!(vm.execute_opcode(Opcode::Display { x: 0, y: 1, height: 3 }), Err(ExecError::MemoryOutOfBounds { address: MEM_SIZE + 1 }));
+            assert!(vm.gfx_memory.iter().all(|&pixel| !pixel));
+            assert_eq!(vm.registers[0xF], 0x42);
+            assert_eq!(vm.take_dirty(), None);
+
+            // Rows clipped off the bottom are never read, so they can't be out of bounds.
+            vm.registers[1] = 31;
+            vm.execute_opcode(Opcode::Display { x: 0, y: 1, height: 3 }).unwrap();
+            assert_eq!(&vm.gfx_memory[31 * GFX_SIZE_X..31 * GFX_SIZE_X + 9], &[true, true, true, true, true, true, true, true, false]);
+        }
+    }
+
+    mod disassembly {
+        use super::*;
+
+        #[test]
+        fn opcode_mnemonics() {
+            let cases = [
+                (Opcode::ClearDisplay, "CLS"),
+                (Opcode::Return, "RET"),
+                (Opcode::Jump { address: 0x09DE }, "JP 0x9DE"),
+                (Opcode::Call { address: 0x07A9 }, "CALL 0x7A9"),
+                (Opcode::SkipIfEqual { register: 0x4, value: 0x2F }, "SE V4, 0x2F"),
+                (Opcode::SkipIfRegistersNotEqual { register1: 0x3, register2: 0x7 }, "SNE V3, V7"),
+                (Opcode::SetRegister { register: 0xE, value: 0x72 }, "LD VE, 0x72"),
+                (Opcode::CopyRegister { target: 0x3, source: 0x7 }, "LD V3, V7"),
+                (Opcode::BitXor { target: 0x3, other: 0x7 }, "XOR V3, V7"),
+                (Opcode::SubtractRegister { target: 0x3, other: 0x7 }, "SUB V3, V7"),
+                (Opcode::RightShift { target: 0x3, source: 0x7 }, "SHR V3, V7"),
+                (Opcode::SetIndexRegister { value: 0x0428 }, "LD I, 0x428"),
+                (Opcode::OffsetJump { address: 0x03FC }, "JP V0, 0x3FC"),
+                (Opcode::Rand { register: 0x1, mask: 0xF0 }, "RND V1, 0xF0"),
+                (Opcode::Display { x: 0x0, y: 0x1, height: 0xE }, "DRW V0, V1, 0xE"),
+                (Opcode::SkipIfKeyPressed { key: 0x2 }, "SKP V2"),
+                (Opcode::SkipIfKeyNotPressed { key: 0x2 }, "SKNP V2"),
+                (Opcode::GetDelayTimer { register: 0x3 }, "LD V3, DT"),
+                (Opcode::SetSoundTimer { value: 0xE }, "LD ST, VE"),
+                (Opcode::AwaitKeypress { register: 0x9 }, "LD V9, K"),
+                (Opcode::SetIndexToFont { register: 0x8 }, "LD F, V8"),
+                (Opcode::StoreDecimal { register: 0x8 }, "LD B, V8"),
+                (Opcode::MemDump { max_register: 0x8 }, "LD [I], V8"),
+                (Opcode::MemLoad { max_register: 0x9 }, "LD V9, [I]"),
+            ];
+
+            for &(ref opcode, expected) in cases.iter() {
+                assert_eq!(opcode.to_string(), expected);
+            }
+        }
+
+        #[test]
+        fn rom() {
+            let rom = [0x00, 0xE0, 0x6E, 0x72, 0xFF, 0xFF, 0x12, 0x00, 0xAB];
+            assert_eq!(disassemble_rom(&rom), vec![
+                (0x200, "CLS".to_string()),
+                (0x202, "LD VE, 0x72".to_string()),
+                (0x204, "DW 0xFFFF".to_string()),
+                (0x206, "JP 0x200".to_string()),
+                (0x208, "DB 0xAB".to_string()),
+            ]);
+        }
+
+        #[test]
+        fn rom_larger_than_memory() {
+            let rom = vec![0x00; 0x10000];
+            let disassembly = disassemble_rom(&rom);
+            assert_eq!(disassembly.len(), (MEM_SIZE - 0x200) / 2);
+            assert_eq!(disassembly.last(), Some(&((MEM_SIZE - 2) as u16, "DW 0x0000".to_string())));
+        }
     }
 
     mod opcode_decoding {
@@ -705,6 +2618,8 @@ mod test {
                         Some(decoded) => assert_eq!(decoded, $expected, "expected {:?} to decode to {:?}, but got {:?}", $opcode, $expected, decoded),
                         None => panic!("couldn't decode opcode {}", $opcode),
                     }
+                    // Encoding must give back the exact word that was decoded.
+                    assert_eq!($expected.encode(), $opcode, "expected {:?} to encode to {:#06X}", $expected, $opcode);
                 }
             );
             ($opcode:expr => $expected:expr, $($chain_opcode:expr => $chain_expected:expr),+$(,)*) => {{
@@ -731,7 +2646,7 @@ mod test {
                 0x8375 => Opcode::SubtractRegister { target: 0x3, other: 0x7 },
                 0x8376 => Opcode::RightShift { target: 0x3, source: 0x7 },
                 0x8377 => Opcode::AltSubtractRegister { target: 0x3, other: 0x7 },
-                0x8378 => Opcode::LeftShift { target: 0x3, source: 0x7 },
+                0x837E => Opcode::LeftShift { target: 0x3, source: 0x7 },
                 0x9370 => Opcode::SkipIfRegistersNotEqual { register1: 0x3, register2: 0x7 },
                 0xA428 => Opcode::SetIndexRegister { value: 0x0428 },
                 0xB3FC => Opcode::OffsetJump { address: 0x03FC },
@@ -751,4 +2666,19 @@ mod test {
             }
         }
     }
+
+    mod opcode_encoding {
+        use super::*;
+
+        #[test]
+        fn round_trips() {
+            // Every word that decodes must encode to something that decodes to the same opcode.
+            // (Words with don't-care bits, like 0x5xy1, encode to their canonical form.)
+            for word in 0..=0xFFFFu16 {
+                if let Some(decoded) = decode_opcode(word) {
+                    assert_eq!(decode_opcode(decoded.encode()), Some(decoded), "{:#06X} didn't round-trip", word);
+                }
+            }
+        }
+    }
 }
\ No newline at end of file
@@ -1,5 +1,61 @@
+extern crate bincode;
+extern crate rand;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+
 mod chip8;
 
+use std::env;
+use std::fs;
+use std::process;
+
+// Cycles to run a ROM for when none are given.
+const DEFAULT_CYCLES: usize = 1000;
+
+fn usage() -> ! {
+    eprintln!("usage: chip8-int <rom> [cycles]");
+    eprintln!("       chip8-int --disassemble <rom>");
+    process::exit(2);
+}
+
+fn read_rom(path: &str) -> Vec<u8> {
+    match fs::read(path) {
+        Ok(rom) => rom,
+        Err(error) => {
+            eprintln!("couldn't read {}: {}", path, error);
+            process::exit(1);
+        }
+    }
+}
+
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if args.len() == 2 && args[0] == "--disassemble" {
+        for (address, text) in chip8::disassemble_rom(&read_rom(&args[1])) {
+            println!("{:#06X}  {}", address, text);
+        }
+        return;
+    }
+
+    // Runs the ROM headless and prints the screen it leaves behind.
+    let cycles = match args.len() {
+        1 => DEFAULT_CYCLES,
+        2 => args[1].parse().unwrap_or_else(|_| usage()),
+        _ => usage(),
+    };
+
+    match chip8::Chip8::run_and_capture(&read_rom(&args[0]), cycles) {
+        Ok(framebuffer) => {
+            for row in framebuffer.chunks(chip8::GFX_SIZE_X) {
+                let line: String = row.iter().map(|&pixel| if pixel { '#' } else { '.' }).collect();
+                println!("{}", line);
+            }
+        },
+        Err(error) => {
+            eprintln!("error: {}", error);
+            process::exit(1);
+        }
+    }
 }